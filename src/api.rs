@@ -2,13 +2,13 @@ use async_graphql::{
     http::{playground_source, GraphQLPlaygroundConfig},
     Executor,
 };
-use async_graphql_poem::{GraphQLBatchRequest, GraphQLBatchResponse};
+use async_graphql_poem::{GraphQLBatchRequest, GraphQLBatchResponse, GraphQLSubscription};
 use poem::{
     async_trait, handler, http::StatusCode, web::Html, Endpoint, FromRequest, IntoResponse,
     Request, Response, Result,
 };
 
-use crate::state::State;
+use crate::state::{Orgs, State};
 
 #[derive(Default)]
 pub struct GraphQL {
@@ -48,6 +48,34 @@ impl Endpoint for GraphQL {
     }
 }
 
+/// GraphQL over WebSocket endpoint serving org subscriptions at `/ws/{org}`
+#[derive(Default)]
+pub struct GraphQLWs {
+    pub orgs: Orgs,
+}
+
+#[async_trait]
+impl Endpoint for GraphQLWs {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let uri = req.uri().to_string();
+        let uri = uri.split('/').collect::<Vec<&str>>();
+        let org = *uri.get(2).unwrap_or(&"");
+        let schema;
+        {
+            schema = self.orgs.orgs.read().await.get(org).map(|s| s.gql.clone())
+        }
+        match schema {
+            Some(gql) => GraphQLSubscription::new(gql)
+                .call(req)
+                .await
+                .map(IntoResponse::into_response),
+            None => Err(poem::Error::from(StatusCode::NOT_FOUND)),
+        }
+    }
+}
+
 #[handler]
 pub async fn graphql_playground(req: &Request) -> poem::Result<Response> {
     let uri = req.uri().to_string();
@@ -55,9 +83,10 @@ pub async fn graphql_playground(req: &Request) -> poem::Result<Response> {
     let org = *uri.get(2).unwrap_or(&"");
     match org {
         "" => Ok(Html(playground_source(GraphQLPlaygroundConfig::new("/api/"))).into_response()),
-        org => Ok(Html(playground_source(GraphQLPlaygroundConfig::new(
-            format!("/api/{}", org).as_str(),
-        )))
+        org => Ok(Html(playground_source(
+            GraphQLPlaygroundConfig::new(format!("/api/{}", org).as_str())
+                .subscription_endpoint(format!("/ws/{}", org).as_str()),
+        ))
         .into_response()),
     }
 }
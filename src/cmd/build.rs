@@ -0,0 +1,187 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+};
+
+use serde::Deserialize;
+
+/// Build settings of `riwaq dev`, read from `[package.metadata.riwaq]` or
+/// `[workspace.metadata.riwaq]` in `./Cargo.toml` and overridden by command line flags
+#[derive(clap::Args, Deserialize, Debug, Default, Clone)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct BuildConfig {
+    /// Build with the release profile
+    #[arg(long)]
+    pub release: bool,
+    /// Build with the given cargo profile
+    #[arg(long, conflicts_with = "release")]
+    pub profile: Option<String>,
+    /// Comma separated list of features to activate
+    #[arg(long, value_delimiter = ',')]
+    pub features: Vec<String>,
+    /// Workspace member directory to build, each member is served as its own org
+    #[arg(long = "member")]
+    pub members: Vec<String>,
+    /// Extra arguments passed to `cargo build`
+    #[arg(last = true)]
+    pub cargo_args: Vec<String>,
+}
+
+impl BuildConfig {
+    pub fn load(cli: BuildConfig) -> Result<Self, Box<dyn Error>> {
+        let manifest = manifest(Path::new("."))?;
+        let cfg = ["package", "workspace"]
+            .iter()
+            .find_map(|k| {
+                manifest
+                    .get(*k)
+                    .and_then(|t| t.get("metadata"))
+                    .and_then(|m| m.get("riwaq"))
+                    .cloned()
+            })
+            .map(|v| v.try_into::<BuildConfig>())
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Self {
+            release: cli.release || cfg.release,
+            profile: cli.profile.or(cfg.profile),
+            features: if cli.features.is_empty() {
+                cfg.features
+            } else {
+                cli.features
+            },
+            members: if cli.members.is_empty() {
+                cfg.members
+            } else {
+                cli.members
+            },
+            cargo_args: if cli.cargo_args.is_empty() {
+                cfg.cargo_args
+            } else {
+                cli.cargo_args
+            },
+        })
+    }
+
+    /// Directory and package name of every module to build
+    pub fn packages(&self) -> Result<Vec<(PathBuf, String)>, Box<dyn Error>> {
+        if self.members.is_empty() {
+            return Ok(vec![(PathBuf::from("."), package_name()?)]);
+        }
+        self.members
+            .iter()
+            .map(|m| Ok((PathBuf::from(m), package_name_at(Path::new(m))?)))
+            .collect()
+    }
+
+    fn profile_dir(&self) -> &str {
+        match (&self.profile, self.release) {
+            (Some(p), _) if p == "dev" => "debug",
+            (Some(p), _) => p.as_str(),
+            (None, true) => "release",
+            (None, false) => "debug",
+        }
+    }
+
+    /// Path of the wasm cargo produces for `package`
+    pub fn artifact(&self, package: &str) -> PathBuf {
+        PathBuf::from(format!(
+            "target/wasm32-unknown-unknown/{}/{}.wasm",
+            self.profile_dir(),
+            package.replace('-', "_")
+        ))
+    }
+
+    pub fn cargo_args(&self, packages: &[(PathBuf, String)]) -> Vec<String> {
+        let mut args = vec![
+            "build".to_string(),
+            "--target".to_string(),
+            "wasm32-unknown-unknown".to_string(),
+        ];
+        if let Some(profile) = &self.profile {
+            args.extend(["--profile".to_string(), profile.to_owned()]);
+        } else if self.release {
+            args.push("--release".to_string());
+        }
+        if !self.features.is_empty() {
+            args.extend(["--features".to_string(), self.features.join(",")]);
+        }
+        if !self.members.is_empty() {
+            for (_, p) in packages {
+                args.extend(["-p".to_string(), p.to_owned()]);
+            }
+        }
+        args.extend(self.cargo_args.iter().cloned());
+        args
+    }
+}
+
+fn manifest(dir: &Path) -> Result<toml::Table, Box<dyn Error>> {
+    Ok(fs::read_to_string(dir.join("Cargo.toml"))?.parse::<toml::Table>()?)
+}
+
+fn package_name_at(dir: &Path) -> Result<String, Box<dyn Error>> {
+    Ok(manifest(dir)?
+        .get("package")
+        .and_then(|p| p.get("name"))
+        .and_then(|n| n.as_str())
+        .unwrap_or("riwaq")
+        .to_string())
+}
+
+/// Package name from `./Cargo.toml`, also the default org name of the built module
+pub fn package_name() -> Result<String, Box<dyn Error>> {
+    package_name_at(Path::new("."))
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Starts a build of `packages`, moving each module to `./dist/{package}/` once done.
+/// Compiler output goes straight to the terminal.
+pub fn build_project(config: &BuildConfig, packages: &[(PathBuf, String)]) -> Child {
+    let cargo = config
+        .cargo_args(packages)
+        .iter()
+        .map(|a| shell_quote(a))
+        .collect::<Vec<String>>()
+        .join(" ");
+    let moves = packages
+        .iter()
+        .map(|(_, p)| {
+            format!(
+                "mkdir -p ./dist/{p} && mv {} ./dist/{p}/{p}.wasm",
+                config.artifact(p).display()
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(" && ");
+    Command::new("sh")
+        .arg("-c")
+        .arg(format!("cargo {cargo} && {moves}"))
+        .spawn()
+        .expect("Failed to build project")
+}
+
+/// Builds the current package in release mode, returning the path of the produced wasm
+pub fn build_release() -> Result<PathBuf, Box<dyn Error>> {
+    let mut config = BuildConfig::load(BuildConfig {
+        release: true,
+        ..Default::default()
+    })?;
+    config.profile = None;
+    config.members.clear();
+
+    let packages = config.packages()?;
+    let status = Command::new("cargo")
+        .args(config.cargo_args(&packages))
+        .status()?;
+    if !status.success() {
+        return Err(format!("cargo build failed with {status}").into());
+    }
+    Ok(config.artifact(&packages[0].1))
+}
@@ -0,0 +1,40 @@
+use std::{error::Error, sync::Arc};
+
+use serde_json::{json, Value};
+
+use crate::{server::init_operator, state::StorageConfig};
+
+use super::build::{build_release, package_name};
+
+/// Builds the project in release mode, uploads it to the org's `STORAGE_*` bucket
+/// and asks the riwaq server at `server` to load it.
+pub async fn deploy(org: Option<String>, server: String) -> Result<(), Box<dyn Error>> {
+    let pname = package_name()?;
+    let org = org.unwrap_or_else(|| pname.clone());
+
+    let wasm = std::fs::read(build_release()?)?;
+
+    let op = init_operator(Arc::new(StorageConfig::from_env()?.for_org(&org)))?;
+    op.write(&format!("{pname}.wasm"), wasm)
+        .await
+        .map_err(|e| e.with_context("op", "error uploading module"))?;
+    println!("Uploaded {pname}.wasm to org '{org}'");
+
+    let res = reqwest::Client::new()
+        .post(format!("{}/api/", server.trim_end_matches('/')))
+        .json(&json!({
+            "query": "query ($org: String!) { loadWasm(org: $org) }",
+            "variables": { "org": org }
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Value>()
+        .await?;
+    if let Some(errors) = res.get("errors") {
+        return Err(format!("server failed to load '{org}': {errors}").into());
+    }
+    println!("Deployed '{org}' on {server}");
+
+    Ok(())
+}
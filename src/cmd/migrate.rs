@@ -0,0 +1,31 @@
+use std::{collections::HashMap, error::Error};
+
+use crate::wasm::wasm_loader::load_sql;
+
+use super::schema::read_target;
+
+/// Migrates the database of a `.wasm` file or of an org read from the `STORAGE_*` storage,
+/// with `plan` the statements are printed instead of being run
+pub async fn migrate(target: String, plan: bool) -> Result<(), Box<dyn Error>> {
+    let (org, modules) = read_target(&target).await?;
+    let sql = load_sql(&org, modules, &HashMap::new()).await?;
+
+    if !plan {
+        return sql.migrate(org).await;
+    }
+
+    for t in sql.plan(&org).await? {
+        if t.statements.is_empty() {
+            println!("-- {}.{}: up to date", org, t.table);
+            continue;
+        }
+        println!("-- {}.{}", org, t.table);
+        for d in t.destructive {
+            println!("-- destructive: {}", d);
+        }
+        for stmt in t.statements {
+            println!("{}", stmt);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,6 @@
+pub mod build;
+pub mod deploy;
+pub mod migrate;
+pub mod scaffold;
+pub mod schema;
+pub mod seed;
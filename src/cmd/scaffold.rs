@@ -0,0 +1,96 @@
+use std::{error::Error, fs, path::PathBuf};
+
+const CARGO_TOML: &str = r#"[package]
+name = "{{name}}"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+crate-type = ["cdylib"]
+
+[dependencies]
+riwaq = "0.1.0"
+serde = { version = "1", features = ["derive"] }
+serde_json = "1.0"
+"#;
+
+const LIB_RS: &str = r#"use riwaq::{handler, table};
+use serde::{Deserialize, Serialize};
+
+/// Exported as `riwaq_table_ddl_todo`, created and migrated by riwaq on every load
+#[table]
+pub struct Todo {
+    pub id: u64,
+    pub title: String,
+    pub done: bool,
+}
+
+#[derive(Deserialize)]
+pub struct HelloInput {
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct HelloOutput {
+    pub message: String,
+}
+
+/// Exposed as the `hello` query of the org's GraphQL API
+#[handler]
+pub fn hello(input: HelloInput) -> HelloOutput {
+    HelloOutput {
+        message: format!("Hello, {}!", input.name),
+    }
+}
+"#;
+
+const ENV: &str = r#"RUST_LOG=info
+LISTEN_ADDR=0.0.0.0:50051
+
+WASM_COMPILER=singlepass
+
+# local sqlite file, point it to databend://... or postgres://... for a shared database
+DB_URL=sqlite://./{{org}}.db
+"#;
+
+const GITIGNORE: &str = r#"target/
+dist/
+*.db
+"#;
+
+/// Generates a riwaq project in `./{name}`, ready for `riwaq dev`
+pub fn new_project(name: &str) -> Result<(), Box<dyn Error>> {
+    // the name is used as the org and database name, keep it a plain identifier
+    if !name.starts_with(|c: char| c.is_ascii_lowercase())
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(format!(
+            "invalid project name '{name}': use lowercase letters, digits and '_', starting with a letter"
+        )
+        .into());
+    }
+
+    let root = PathBuf::from(name);
+    if root.exists() {
+        return Err(format!("destination '{}' already exists", root.display()).into());
+    }
+
+    fs::create_dir_all(root.join("src"))?;
+    fs::write(
+        root.join("Cargo.toml"),
+        CARGO_TOML.replace("{{name}}", name),
+    )?;
+    fs::write(root.join("src").join("lib.rs"), LIB_RS)?;
+    fs::write(root.join(".env"), ENV)?;
+    fs::write(root.join(".gitignore"), GITIGNORE)?;
+
+    println!("Created riwaq project '{name}'");
+    println!();
+    println!("  rustup target add wasm32-unknown-unknown");
+    println!("  cd {name}");
+    println!("  riwaq dev");
+
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use std::{error::Error, fs, path::Path};
+
+use tokio::sync::broadcast;
+
+use crate::{
+    gql::gql_loader::Gql,
+    state::StorageConfig,
+    wasm::{
+        wasm_cache::load_module,
+        wasm_loader::{org_store, read_modules},
+        wasm_pool::{InstancePool, PoolConfig},
+    },
+};
+
+const INTROSPECTION_QUERY: &str = r#"
+query IntrospectionQuery {
+  __schema {
+    queryType { name }
+    mutationType { name }
+    subscriptionType { name }
+    types { ...FullType }
+    directives {
+      name
+      description
+      locations
+      args { ...InputValue }
+    }
+  }
+}
+
+fragment FullType on __Type {
+  kind
+  name
+  description
+  fields(includeDeprecated: true) {
+    name
+    description
+    args { ...InputValue }
+    type { ...TypeRef }
+    isDeprecated
+    deprecationReason
+  }
+  inputFields { ...InputValue }
+  interfaces { ...TypeRef }
+  enumValues(includeDeprecated: true) {
+    name
+    description
+    isDeprecated
+    deprecationReason
+  }
+  possibleTypes { ...TypeRef }
+}
+
+fragment InputValue on __InputValue {
+  name
+  description
+  type { ...TypeRef }
+  defaultValue
+}
+
+fragment TypeRef on __Type {
+  kind
+  name
+  ofType {
+    kind
+    name
+    ofType {
+      kind
+      name
+      ofType {
+        kind
+        name
+        ofType {
+          kind
+          name
+          ofType {
+            kind
+            name
+            ofType {
+              kind
+              name
+              ofType { kind name }
+            }
+          }
+        }
+      }
+    }
+  }
+}
+"#;
+
+/// Org name and modules of `target`, a `.wasm` file named after its org or an org read from
+/// the `STORAGE_*` storage
+pub async fn read_target(target: &str) -> Result<(String, Vec<Vec<u8>>), Box<dyn Error>> {
+    let path = Path::new(target);
+    Ok(if path.is_file() {
+        (
+            path.file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("riwaq")
+                .to_string(),
+            vec![fs::read(path)?],
+        )
+    } else {
+        let modules = read_modules(&StorageConfig::from_env()?.for_org(target)).await?;
+        (target.to_string(), modules)
+    })
+}
+
+/// Prints the GraphQL schema of a `.wasm` file, or of an org read from the `STORAGE_*`
+/// storage, as SDL or as introspection JSON. Modules are only instantiated, no database
+/// connection is made.
+pub async fn print_schema(target: String, json: bool) -> Result<(), Box<dyn Error>> {
+    let (org, modules) = read_target(&target).await?;
+
+    let pool_config = PoolConfig {
+        min: 1,
+        max: 1,
+        ..PoolConfig::from_env(&org)
+    };
+    let (compiler_name, store) = org_store(&pool_config);
+
+    let mut gql = Gql::new(broadcast::channel(1).0);
+    for wasm in modules {
+        let module = load_module(&store, &wasm, compiler_name, &pool_config)?;
+        let pool = InstancePool::new(&org, module, gql.events(), pool_config.clone())?;
+        let instance = pool.get().await?;
+        gql = gql.load_handlers(pool.clone(), &instance)?;
+    }
+    let schema = gql.build_schema()?;
+
+    if json {
+        let res = schema.execute(INTROSPECTION_QUERY).await;
+        println!("{}", serde_json::to_string_pretty(&res)?);
+    } else {
+        println!("{}", schema.sdl());
+    }
+
+    Ok(())
+}
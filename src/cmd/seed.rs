@@ -0,0 +1,21 @@
+use std::{collections::HashMap, error::Error};
+
+use crate::wasm::wasm_loader::load_sql;
+
+use super::schema::read_target;
+
+/// Applies the `riwaq_seed_*` exports of a `.wasm` file or of an org read from the `STORAGE_*`
+/// storage again, their tables are expected to be migrated already
+pub async fn seed(target: String) -> Result<(), Box<dyn Error>> {
+    let (org, modules) = read_target(&target).await?;
+    let sql = load_sql(&org, modules, &HashMap::new()).await?;
+
+    let seeds = sql.seed(&org).await?;
+    if seeds.is_empty() {
+        println!("-- {}: no seeds", org);
+    }
+    for (name, rows) in seeds {
+        println!("-- {}: seed {} applied, {} rows", org, name, rows);
+    }
+    Ok(())
+}
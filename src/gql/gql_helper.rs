@@ -1,11 +1,16 @@
-use std::error::Error;
+use std::{collections::HashSet, error::Error};
 
-use async_graphql::dynamic::{
-    Field, FieldFuture, FieldValue, InputObject, InputValue, ListAccessor, Object, ObjectAccessor,
-    ResolverContext, TypeRef, ValueAccessor,
+use async_graphql::{
+    dynamic::{
+        Field, FieldFuture, FieldValue, InputObject, InputValue, ListAccessor, Object,
+        ObjectAccessor, ResolverContext, TypeRef, ValueAccessor,
+    },
+    ErrorExtensions,
 };
 use serde_json::{Map, Value};
 
+use crate::wasm::wasm_pool::HandlerError;
+
 #[derive(Debug)]
 pub enum TypeRefKind {
     Named,
@@ -18,6 +23,36 @@ pub enum TypeRefKind {
 
 pub type InputType = (String, Vec<InputValue>, Vec<InputObject>, TypeRefKind);
 
+/// Custom scalars of the values sql queries return, named after the types handlers use
+pub const SCALARS: [(&str, &str); 4] = [
+    ("Decimal", "decimal as a string keeping its scale"),
+    ("DateTime", "RFC 3339 timestamp with its offset"),
+    ("Date", "date as YYYY-MM-DD"),
+    ("JSON", "any JSON value, variant columns, maps and tuples"),
+];
+
+fn scalar(t: &str) -> Option<&'static str> {
+    Some(match t {
+        "Decimal" | "BigDecimal" => "Decimal",
+        "DateTime" | "NaiveDateTime" => "DateTime",
+        "Date" | "NaiveDate" => "Date",
+        "JSON" | "Json" | "Value" => "JSON",
+        _ => return None,
+    })
+}
+
+/// Adds the [`SCALARS`] handler metadata refers to to `used`
+pub fn metadata_scalars(metadata: &Value, used: &mut HashSet<&'static str>) {
+    match metadata {
+        Value::String(t) => used.extend(scalar(t)),
+        Value::Object(object) => object
+            .iter()
+            .filter(|(k, _)| *k != "_name_" && *k != "container")
+            .for_each(|(_, v)| metadata_scalars(v, used)),
+        _ => {}
+    }
+}
+
 pub fn value_to_gql_input_type(
     name: String,
     metadata: serde_json::Value,
@@ -30,7 +65,10 @@ pub fn value_to_gql_input_type(
                 | "u128" | "usize" => TypeRef::INT,
                 "f32" | "f64" => TypeRef::FLOAT,
                 "char" | "String" => TypeRef::STRING,
-                _ => return Err(format!("invalid metadata type: {}", metadata).into()),
+                t => match scalar(t) {
+                    Some(t) => t,
+                    None => return Err(format!("invalid metadata type: {}", metadata).into()),
+                },
             };
             Ok((
                 t.to_owned(),
@@ -185,7 +223,10 @@ pub fn value_to_gql_output_type(
                 | "u128" | "usize" => TypeRef::INT,
                 "f32" | "f64" => TypeRef::FLOAT,
                 "char" | "String" => TypeRef::STRING,
-                _ => return Err(format!("invalid metadata type: {}", metadata).into()),
+                t => match scalar(t) {
+                    Some(t) => t,
+                    None => return Err(format!("invalid metadata type: {}", metadata).into()),
+                },
             };
             Ok((
                 t.to_owned(),
@@ -443,3 +484,12 @@ pub fn ser_params(ctx: ResolverContext) -> Value {
         .collect::<Vec<()>>();
     Value::Object(m)
 }
+
+pub fn handler_error(e: HandlerError) -> async_graphql::Error {
+    let code = match e {
+        HandlerError::Timeout => "HANDLER_TIMEOUT",
+        HandlerError::MemoryLimit(_) => "HANDLER_MEMORY_LIMIT",
+        HandlerError::Failed(_) => "HANDLER_ERROR",
+    };
+    async_graphql::Error::new(e.to_string()).extend_with(|_, ext| ext.set("code", code))
+}
@@ -1,37 +1,74 @@
-use std::error::Error;
+use std::{collections::HashSet, error::Error};
 
-use async_graphql::dynamic::{Field, FieldFuture, InputObject, Object, Schema, TypeRef};
+use async_graphql::{
+    dynamic::{
+        Field, FieldFuture, FieldValue, InputObject, Object, Scalar, Schema, Subscription,
+        SubscriptionField, SubscriptionFieldFuture, TypeRef,
+    },
+    futures_util::stream,
+};
 use serde::Deserialize;
 use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
 use wasmer::{Extern, Instance, MemoryView};
 
 use crate::{
-    gql::gql_helper::{ser_params, value_to_gql_input_type, value_to_gql_output_type},
-    wasm::wasm_helper::call_wasm,
+    gql::gql_helper::{
+        handler_error, metadata_scalars, ser_params, value_to_gql_input_type,
+        value_to_gql_output_type, SCALARS,
+    },
+    state::OrgEvents,
+    wasm::wasm_pool::WasmPool,
 };
 
 pub struct Gql {
     input_objects: Vec<InputObject>,
     output_objects: Vec<Object>,
     query: Object,
-    // mutation: Object,
-    // subscription: Subscription,
-    contain_fields: bool,
+    mutation: Object,
+    subscription: Subscription,
+    events: OrgEvents,
+    /// custom scalars the handlers' types refer to
+    scalars: HashSet<&'static str>,
+    contain_queries: bool,
+    contain_mutations: bool,
+    contain_subscriptions: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum HandlerKind {
+    #[default]
+    Query,
+    Mutation,
+    Subscription,
 }
 
 impl Gql {
-    pub fn new() -> Self {
+    pub fn new(events: OrgEvents) -> Self {
         Self {
             input_objects: vec![],
             output_objects: vec![],
             query: Object::new("Query"),
-            // mutation: Object::new("Mutation"),
-            // subscription: Subscription::new("Subscription"),
-            contain_fields: false,
+            mutation: Object::new("Mutation"),
+            subscription: Subscription::new("Subscription"),
+            events,
+            scalars: HashSet::new(),
+            contain_queries: false,
+            contain_mutations: false,
+            contain_subscriptions: false,
         }
     }
 
-    pub fn load_handlers(mut self, instance: Instance) -> Result<Self, Box<dyn Error>> {
+    pub fn events(&self) -> OrgEvents {
+        self.events.clone()
+    }
+
+    pub fn load_handlers(
+        mut self,
+        pool: WasmPool,
+        instance: &Instance,
+    ) -> Result<Self, Box<dyn Error>> {
         let handlers_metadata = instance
             .exports
             .iter()
@@ -58,12 +95,16 @@ impl Gql {
                 struct Metadata {
                     input: serde_json::Value,
                     output: serde_json::Value,
+                    #[serde(default)]
+                    kind: HandlerKind,
                 }
 
                 let res = String::from_utf8_lossy(data.as_slice());
 
                 let metadata = serde_json::from_str::<Metadata>(&res).unwrap();
 
+                metadata_scalars(&metadata.input, &mut self.scalars);
+                metadata_scalars(&metadata.output, &mut self.scalars);
                 let input_fields =
                     value_to_gql_input_type("input".to_string(), metadata.input.clone())?;
                 let output_fields = value_to_gql_output_type(
@@ -80,59 +121,170 @@ impl Gql {
                     .ok_or("")?
                     .to_string();
 
-                let instance = instance.clone();
-
-                let mut field = Field::new(
-                    f_name.clone(),
-                    TypeRef::named_nn(output_fields.0),
-                    move |ctx| {
-                        let r: Result<Option<async_graphql::Value>, async_graphql::Error> =
-                            (|| {
-                                let instance = instance.clone();
-                                let e = instance.exports.clone();
-                                let e2 = instance.exports;
-                                let memory = e.get_memory("memory")?;
-                                let memory_view: MemoryView<u8> = memory.view();
-                                let res = call_wasm(
-                                    e2,
-                                    memory_view,
-                                    format!("riwaq_handler_{}", f_name.clone()),
-                                    ser_params(ctx),
-                                )
-                                .map_err(|e| {
-                                    async_graphql::Error::new_with_source(e.to_string())
-                                })?;
-                                Ok(Some(async_graphql::Value::from_json(res)?))
-                            })();
-                        FieldFuture::new(async move { r })
-                    },
-                );
-                for f in input_fields.1 {
-                    field = field.argument(f);
+                let pool = pool.clone();
+
+                match metadata.kind {
+                    HandlerKind::Subscription => {
+                        let events = self.events.clone();
+                        let mut field = SubscriptionField::new(
+                            f_name.clone(),
+                            TypeRef::named_nn(output_fields.0),
+                            move |ctx| {
+                                let pool = pool.clone();
+                                let f_name = f_name.clone();
+                                let args = ser_params(ctx);
+                                let rx = events.subscribe();
+                                SubscriptionFieldFuture::new(async move {
+                                    Ok(stream::unfold(rx, move |mut rx| {
+                                        let pool = pool.clone();
+                                        let f_name = f_name.clone();
+                                        let args = args.clone();
+                                        async move {
+                                            loop {
+                                                let event = match rx.recv().await {
+                                                    Ok(e) => e,
+                                                    Err(RecvError::Lagged(n)) => {
+                                                        tracing::warn!(
+                                                            "subscription {} skipped {} events",
+                                                            f_name,
+                                                            n
+                                                        );
+                                                        continue;
+                                                    }
+                                                    Err(RecvError::Closed) => return None,
+                                                };
+                                                if event["event"] != f_name.as_str() {
+                                                    continue;
+                                                }
+                                                match on_event(&pool, &f_name, &args, event).await {
+                                                    Ok(None) => continue,
+                                                    Ok(Some(v)) => return Some((Ok(v), rx)),
+                                                    Err(e) => return Some((Err(e), rx)),
+                                                }
+                                            }
+                                        }
+                                    }))
+                                })
+                            },
+                        );
+                        for f in input_fields.1 {
+                            field = field.argument(f);
+                        }
+                        self.subscription = self.subscription.field(field);
+                        self.contain_subscriptions = true;
+                    }
+                    kind => {
+                        let mut field = Field::new(
+                            f_name.clone(),
+                            TypeRef::named_nn(output_fields.0),
+                            move |ctx| {
+                                let pool = pool.clone();
+                                let f_name = f_name.clone();
+                                let args = ser_params(ctx);
+                                FieldFuture::new(async move {
+                                    let res = pool
+                                        .call(format!("riwaq_handler_{}", f_name), args)
+                                        .await
+                                        .map_err(handler_error)?;
+                                    Ok(Some(async_graphql::Value::from_json(res)?))
+                                })
+                            },
+                        );
+                        for f in input_fields.1 {
+                            field = field.argument(f);
+                        }
+                        if let HandlerKind::Mutation = kind {
+                            self.mutation = self.mutation.field(field);
+                            self.contain_mutations = true;
+                        } else {
+                            self.query = self.query.field(field);
+                            self.contain_queries = true;
+                        }
+                    }
                 }
-                self.query = self.query.field(field);
                 self.input_objects.extend(input_fields.2);
                 self.output_objects.extend(output_fields.2);
-
-                self.contain_fields = true;
             }
         }
         Ok(self)
     }
 
     pub fn build_schema(self) -> Result<Schema, Box<dyn Error>> {
-        if !self.contain_fields {
-            return Err("wasm does not contain any object".into());
+        // a GraphQL schema needs a query root, mutations and subscriptions alone can't build one
+        if !self.contain_queries {
+            return Err("wasm does not contain any query handler".into());
         }
 
-        let mut schema = Schema::build(self.query.type_name(), None, None).register(self.query);
+        let types = self
+            .input_objects
+            .iter()
+            .map(|o| o.type_name().to_string())
+            .chain(
+                self.output_objects
+                    .iter()
+                    .map(|o| o.type_name().to_string()),
+            )
+            .collect::<HashSet<String>>();
+
+        let mut schema = Schema::build(
+            self.query.type_name(),
+            self.contain_mutations.then(|| self.mutation.type_name()),
+            self.contain_subscriptions
+                .then(|| self.subscription.type_name()),
+        )
+        .register(self.query);
+        if self.contain_mutations {
+            schema = schema.register(self.mutation);
+        }
+        if self.contain_subscriptions {
+            schema = schema.register(self.subscription);
+        }
         for io in self.input_objects {
             schema = schema.register(io);
         }
         for o in self.output_objects {
             schema = schema.register(o);
         }
+        // a module type of the same name is registered instead of the scalar
+        for (name, description) in SCALARS {
+            if self.scalars.contains(name) && !types.contains(name) {
+                schema = schema.register(Scalar::new(name).description(description));
+            }
+        }
 
         schema.finish().map_err(|e| e.into())
     }
 }
+
+/// Runs a subscription event through `riwaq_handler_{f_name}` when the module exports it,
+/// the handler receives the subscription arguments along with the event under `event`
+/// and may return `null` to skip it. Without a handler the event data is forwarded as is.
+async fn on_event(
+    pool: &WasmPool,
+    f_name: &str,
+    args: &serde_json::Value,
+    event: serde_json::Value,
+) -> Result<Option<FieldValue<'static>>, async_graphql::Error> {
+    let data = event
+        .get("data")
+        .cloned()
+        .unwrap_or(serde_json::Value::Null);
+
+    let handler = format!("riwaq_handler_{}", f_name);
+    let data = if pool.module().exports().any(|e| e.name() == handler) {
+        let mut body = args.clone();
+        if let Some(o) = body.as_object_mut() {
+            o.insert("event".to_string(), data);
+        }
+        pool.call(handler, body).await.map_err(handler_error)?
+    } else {
+        data
+    };
+
+    if data.is_null() {
+        return Ok(None);
+    }
+    Ok(Some(FieldValue::value(async_graphql::Value::from_json(
+        data,
+    )?)))
+}
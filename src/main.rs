@@ -1,4 +1,5 @@
 mod api;
+mod cmd;
 mod gql;
 mod server;
 mod sql;
@@ -9,7 +10,6 @@ use std::{collections::HashMap, process::Child, str::FromStr, sync::Arc, time::D
 
 use clap::Parser;
 use poem::{listener::TcpListener, Server};
-use std::process::Command;
 
 use notify_debouncer_full::{
     new_debouncer,
@@ -19,6 +19,7 @@ use notify_debouncer_full::{
     },
 };
 
+use cmd::build::{build_project, BuildConfig};
 use server::init_server;
 use state::{StorageConfig, StorageOrgBy};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
@@ -27,21 +28,42 @@ use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 #[command(name = "riwaq")]
 #[command(bin_name = "riwaq")]
 enum RiwaqCli {
-    Dev,
+    Dev(BuildConfig),
     Server,
-}
-
-fn build_project() -> Child {
-    let toml = std::fs::read_to_string("./Cargo.toml")
-        .unwrap()
-        .parse::<toml::Table>()
-        .unwrap();
-    let pname = toml["package"]["name"].as_str().unwrap_or("riwaq");
-    Command::new("sh")
-    .arg("-c")
-        .arg(format!("cargo build --target wasm32-unknown-unknown && mkdir -p ./dist/{pname} && mv target/wasm32-unknown-unknown/debug/{pname}.wasm ./dist/{pname}/{pname}.wasm &> /dev/null"))
-        .spawn()
-        .expect("Failed to build project")
+    /// Create a new riwaq project
+    New {
+        name: String,
+    },
+    /// Build in release mode, upload to remote storage and activate it on a server
+    Deploy {
+        /// target org, defaults to the package name
+        #[arg(long)]
+        org: Option<String>,
+        /// riwaq server to activate the module on
+        #[arg(long, default_value = "http://127.0.0.1:50051")]
+        server: String,
+    },
+    /// Print the GraphQL schema of a module or an org without starting the server
+    Schema {
+        /// path to a `.wasm` file, or an org name read from `STORAGE_*`
+        target: String,
+        /// print introspection JSON instead of SDL
+        #[arg(long)]
+        json: bool,
+    },
+    /// Migrate the database of a module or an org to its table definitions
+    Migrate {
+        /// path to a `.wasm` file, or an org name read from `STORAGE_*`
+        target: String,
+        /// print the statements the migration would run without running them
+        #[arg(long)]
+        plan: bool,
+    },
+    /// Apply the seed data of a module or an org again, e.g. after editing it in dev
+    Seed {
+        /// path to a `.wasm` file, or an org name read from `STORAGE_*`
+        target: String,
+    },
 }
 
 #[tokio::main]
@@ -57,7 +79,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let addr = std::env::var("LISTEN_ADDR").unwrap_or_else(|_| "0.0.0.0:50051".to_string());
     match args {
-        RiwaqCli::Dev => {
+        RiwaqCli::Dev(build_config) => {
+            let build_config = BuildConfig::load(build_config)?;
+            let packages = build_config.packages()?;
+
             let storage = Arc::new(StorageConfig {
                 kind: opendal::Scheme::from_str("fs").unwrap(),
                 opt: HashMap::from([(
@@ -76,26 +101,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             let (route, orgs) = init_server(storage.clone()).await?;
 
-            let build_handle = tokio::spawn(async {
+            let build_handle = tokio::spawn(async move {
                 let (tx, rx) = std::sync::mpsc::channel();
                 let mut watcher = new_debouncer(Duration::from_millis(250), None, tx).unwrap();
-                watcher
-                    .watcher()
-                    .watch(
-                        &{
-                            let mut p = std::env::current_dir().unwrap();
-                            p.push("src");
-                            p
-                        },
-                        RecursiveMode::Recursive,
-                    )
-                    .unwrap();
+                for (dir, _) in &packages {
+                    watcher
+                        .watcher()
+                        .watch(
+                            &{
+                                let mut p = std::env::current_dir().unwrap();
+                                p.push(dir);
+                                p.push("src");
+                                p
+                            },
+                            RecursiveMode::Recursive,
+                        )
+                        .unwrap();
+                }
 
-                let mut c: Child = build_project();
+                let mut c: Child = build_project(&build_config, &packages);
                 for res in rx {
                     if let Ok(_) = res {
                         let _ = c.kill();
-                        c = build_project();
+                        c = build_project(&build_config, &packages);
                     }
                 }
             });
@@ -127,7 +155,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                             .parent()
                                             .and_then(|t| t.file_name().and_then(|f| f.to_str()))
                                             .unwrap();
-                                        let _ = orgs.clone().load_wasm(org, storage.clone()).await;
+                                        let res =
+                                            orgs.clone().load_wasm(org, storage.clone()).await;
+                                        if let Err(e) = res {
+                                            tracing::error!(org = %org, "org can't be reloaded: {}", e);
+                                        }
                                     }
                                 }
                                 _ => continue,
@@ -148,30 +180,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let _ = build_handle.await;
         }
         RiwaqCli::Server => {
-            let (route, _) = init_server(Arc::new(StorageConfig {
-                kind: opendal::Scheme::from_str(
-                    &std::env::var("STORAGE_SCHEME").unwrap_or("fs".to_string()),
-                )
-                .unwrap(),
-                opt: HashMap::from_iter(
-                    std::env::vars()
-                        .filter_map(|(k, v)| {
-                            if k.starts_with("STORAGE_") {
-                                Some((k.trim_start_matches("STORAGE_").to_string(), v))
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<(String, String)>>(),
-                ),
-                org_by: StorageOrgBy::Bucket,
-            }))
-            .await?;
+            let (route, _) = init_server(Arc::new(StorageConfig::from_env()?)).await?;
             let _ = Server::new(TcpListener::bind(addr))
                 .run(route)
                 .await
                 .map_err(|e| dbg!(e));
         }
+        RiwaqCli::New { name } => cmd::scaffold::new_project(&name)?,
+        RiwaqCli::Deploy { org, server } => cmd::deploy::deploy(org, server).await?,
+        RiwaqCli::Schema { target, json } => cmd::schema::print_schema(target, json).await?,
+        RiwaqCli::Migrate { target, plan } => cmd::migrate::migrate(target, plan).await?,
+        RiwaqCli::Seed { target } => cmd::seed::seed(target).await?,
     };
 
     Ok(())
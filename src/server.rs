@@ -8,8 +8,10 @@ use poem::{get, post, Route};
 use tokio::sync::RwLock;
 
 use crate::{
-    api::{graphql_playground, GraphQL},
+    api::{graphql_playground, GraphQL, GraphQLWs},
+    sql::sql_loader::TablePlan,
     state::{Orgs, State, StorageConfig},
+    wasm::wasm_loader::{load_sql, read_modules},
 };
 
 pub struct QueryRoot;
@@ -23,6 +25,26 @@ impl QueryRoot {
             .map(|_| true)
             .map_err(|e| async_graphql::Error::new_with_source(e.to_string()))
     }
+
+    /// Statements loading the org's modules would run on its database, nothing is executed
+    async fn migration_plan(
+        &self,
+        ctx: &Context<'_>,
+        org: String,
+    ) -> async_graphql::Result<Vec<TablePlan>> {
+        let orgs = ctx.data::<Orgs>().unwrap();
+        async {
+            let modules = read_modules(&orgs.storage.for_org(&org)).await?;
+            let pools = match orgs.orgs.read().await.get(&org) {
+                Some(loaded) => loaded.sql_pools.clone(),
+                None => HashMap::new(),
+            };
+            let sql = load_sql(&org, modules, &pools).await?;
+            sql.plan(&org).await
+        }
+        .await
+        .map_err(|e: Box<dyn Error>| async_graphql::Error::new_with_source(e.to_string()))
+    }
 }
 
 pub fn init_operator(storage: Arc<StorageConfig>) -> Result<Operator, Box<dyn Error>> {
@@ -93,13 +115,17 @@ pub async fn init_server(storage: Arc<StorageConfig>) -> Result<(Route, Orgs), B
         if let EntryMode::DIR = meta.mode() {
             let mut orgs = state.orgs.clone();
             let org = de.name().replace('/', "");
-            let _ = orgs.load_wasm(org, storage.clone()).await;
+            // an org failing to load doesn't keep the others from being served
+            if let Err(e) = orgs.load_wasm(org.as_str(), storage.clone()).await {
+                tracing::error!(org = %org, "org can't be loaded: {}", e);
+            }
         };
     }
 
     let app = Route::new()
         .at("/playground*path", get(graphql_playground))
-        .at("/api*path", post(GraphQL { state }));
+        .at("/api*path", post(GraphQL { state }))
+        .at("/ws*path", get(GraphQLWs { orgs: orgs.clone() }));
 
     Ok((app, orgs))
 }
@@ -0,0 +1,174 @@
+use std::error::Error;
+
+use async_graphql::futures_util::{future::BoxFuture, FutureExt};
+
+use super::{
+    databend::{Databend, DatabendConn, DatabendConnParams, DatabendPool},
+    error::SqlError,
+    migration::Dialect,
+    model::{ColumnInfo, Conn, ConnParams, ConnPoolConfig, KeyInfo, Pool, RowStream, DB},
+    postgres::{Postgres, PostgresConn, PostgresConnParams, PostgresPool},
+    sqlite::{Sqlite, SqliteConn, SqliteConnParams, SqlitePool},
+};
+
+/// Connection of whichever backend the org's url points to
+pub enum AnyConn {
+    Databend(DatabendConn),
+    Postgres(PostgresConn),
+    Sqlite(SqliteConn),
+}
+
+impl Conn for AnyConn {
+    fn exec<R>(&self, request: R) -> BoxFuture<Result<i64, SqlError>>
+    where
+        R: ToString + Send,
+    {
+        match self {
+            AnyConn::Databend(c) => c.exec(request),
+            AnyConn::Postgres(c) => c.exec(request),
+            AnyConn::Sqlite(c) => c.exec(request),
+        }
+    }
+
+    fn custom_query(
+        &self,
+        request: String,
+    ) -> BoxFuture<Result<Vec<Vec<serde_json::Value>>, SqlError>> {
+        match self {
+            AnyConn::Databend(c) => c.custom_query(request),
+            AnyConn::Postgres(c) => c.custom_query(request),
+            AnyConn::Sqlite(c) => c.custom_query(request),
+        }
+    }
+
+    fn stream(&self, request: String) -> BoxFuture<Result<RowStream, SqlError>> {
+        match self {
+            AnyConn::Databend(c) => c.stream(request),
+            AnyConn::Postgres(c) => c.stream(request),
+            AnyConn::Sqlite(c) => c.stream(request),
+        }
+    }
+
+    fn discard(&self) {
+        match self {
+            AnyConn::Databend(c) => c.discard(),
+            AnyConn::Postgres(c) => c.discard(),
+            AnyConn::Sqlite(c) => c.discard(),
+        }
+    }
+
+    fn columns(
+        &self,
+        org: String,
+        table: String,
+    ) -> BoxFuture<Result<Option<Vec<ColumnInfo>>, SqlError>> {
+        match self {
+            AnyConn::Databend(c) => c.columns(org, table),
+            AnyConn::Postgres(c) => c.columns(org, table),
+            AnyConn::Sqlite(c) => c.columns(org, table),
+        }
+    }
+
+    fn keys(&self, org: String, table: String) -> BoxFuture<Result<KeyInfo, SqlError>> {
+        match self {
+            AnyConn::Databend(c) => c.keys(org, table),
+            AnyConn::Postgres(c) => c.keys(org, table),
+            AnyConn::Sqlite(c) => c.keys(org, table),
+        }
+    }
+
+    fn dialect(&self) -> Dialect {
+        match self {
+            AnyConn::Databend(c) => c.dialect(),
+            AnyConn::Postgres(c) => c.dialect(),
+            AnyConn::Sqlite(c) => c.dialect(),
+        }
+    }
+}
+
+pub struct AnyConnParams {
+    url: String,
+    org: String,
+}
+impl AnyConnParams {
+    pub fn new(url: String, org: String) -> Self {
+        Self { url, org }
+    }
+}
+
+impl ConnParams for AnyConnParams {}
+
+#[derive(Clone, Debug)]
+pub enum AnyPool {
+    Databend(DatabendPool),
+    Postgres(PostgresPool),
+    Sqlite(SqlitePool),
+}
+
+impl Pool for AnyPool {
+    type ConnType = AnyConn;
+
+    fn conn(&self) -> BoxFuture<Result<AnyConn, Box<dyn Error>>> {
+        async move {
+            Ok(match self {
+                AnyPool::Databend(p) => AnyConn::Databend(p.conn().await?),
+                AnyPool::Postgres(p) => AnyConn::Postgres(p.conn().await?),
+                AnyPool::Sqlite(p) => AnyConn::Sqlite(p.conn().await?),
+            })
+        }
+        .boxed()
+    }
+
+    fn tx_conn(&self) -> BoxFuture<Result<AnyConn, Box<dyn Error>>> {
+        async move {
+            Ok(match self {
+                AnyPool::Databend(p) => AnyConn::Databend(p.tx_conn().await?),
+                AnyPool::Postgres(p) => AnyConn::Postgres(p.tx_conn().await?),
+                AnyPool::Sqlite(p) => AnyConn::Sqlite(p.tx_conn().await?),
+            })
+        }
+        .boxed()
+    }
+
+    fn disconnect(&self) -> BoxFuture<Result<(), Box<dyn Error>>> {
+        match self {
+            AnyPool::Databend(p) => p.disconnect(),
+            AnyPool::Postgres(p) => p.disconnect(),
+            AnyPool::Sqlite(p) => p.disconnect(),
+        }
+    }
+
+    fn dialect(&self) -> Dialect {
+        match self {
+            AnyPool::Databend(p) => p.dialect(),
+            AnyPool::Postgres(p) => p.dialect(),
+            AnyPool::Sqlite(p) => p.dialect(),
+        }
+    }
+}
+
+/// Picks the driver from the url scheme: `databend://`, `postgres://` (`postgresql://`)
+/// or `sqlite://` (`sqlite::memory:`)
+pub struct AnyDB {}
+
+impl DB for AnyDB {
+    type ParamsType = AnyConnParams;
+    type PoolType = AnyPool;
+
+    fn init(params: AnyConnParams) -> Result<AnyPool, Box<dyn Error>> {
+        let scheme = params.url.split(':').next().unwrap_or_default().to_string();
+        match scheme.as_str() {
+            "databend" => Ok(AnyPool::Databend(Databend::init(DatabendConnParams::new(
+                params.url,
+                ConnPoolConfig::from_env(&params.org),
+            ))?)),
+            "postgres" | "postgresql" => Ok(AnyPool::Postgres(Postgres::init(
+                PostgresConnParams::new(params.url, ConnPoolConfig::from_env(&params.org)),
+            )?)),
+            "sqlite" => Ok(AnyPool::Sqlite(Sqlite::init(SqliteConnParams::new(
+                params.url, params.org,
+            ))?)),
+            _ => Err(format!("unsupported database url scheme: {}", scheme).into()),
+        }
+    }
+}
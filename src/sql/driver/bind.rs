@@ -0,0 +1,253 @@
+use std::error::Error;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::migration::Dialect;
+
+/// Bind parameters of a custom statement, `?` placeholders take positional values in order
+/// and `:name` placeholders take named ones. `?|` and `?&` are postgres jsonb operators,
+/// not placeholders.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Params {
+    Positional(Vec<Value>),
+    Named(Map<String, Value>),
+}
+
+/// Custom SQL sent by a module, either plain SQL or a `{"sql": ..., "params": ...}` envelope
+#[derive(Deserialize, Debug, Clone)]
+pub struct CustomSql {
+    pub sql: String,
+    #[serde(default)]
+    pub params: Option<Params>,
+}
+
+impl CustomSql {
+    pub fn parse(request: &str) -> Self {
+        serde_json::from_str(request).unwrap_or_else(|_| Self {
+            sql: request.to_string(),
+            params: None,
+        })
+    }
+
+    /// SQL with every placeholder replaced by the escaped literal of its parameter
+    pub fn bind(&self, dialect: Dialect) -> Result<String, Box<dyn Error>> {
+        let params = match &self.params {
+            Some(params) => params,
+            None => return Ok(self.sql.clone()),
+        };
+
+        let chars = self.sql.chars().collect::<Vec<char>>();
+        let mut res = String::with_capacity(self.sql.len());
+        let mut next = 0;
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            // literals, quoted identifiers and comments are copied untouched
+            let end = match (c, chars.get(i + 1)) {
+                ('\'' | '"' | '`', _) => Some(quoted_end(&chars, i, dialect)),
+                ('$', _) => dollar_quoted_end(&chars, i),
+                ('-', Some('-')) => Some(
+                    (i..chars.len())
+                        .find(|j| chars[*j] == '\n')
+                        .unwrap_or(chars.len()),
+                ),
+                ('/', Some('*')) => Some(
+                    (i + 2..chars.len())
+                        .find(|j| chars[*j] == '*' && chars.get(j + 1) == Some(&'/'))
+                        .map_or(chars.len(), |j| j + 2),
+                ),
+                _ => None,
+            };
+            if let Some(end) = end {
+                res.extend(&chars[i..end]);
+                i = end;
+                continue;
+            }
+
+            match (c, params) {
+                ('?', Params::Positional(values))
+                    if !matches!(chars.get(i + 1), Some('|' | '&')) =>
+                {
+                    let value = values
+                        .get(next)
+                        .ok_or_else(|| format!("missing value for parameter {}", next + 1))?;
+                    res.push_str(&literal(value, dialect));
+                    next += 1;
+                    i += 1;
+                }
+                // `::` is a postgres cast, not a parameter
+                (':', Params::Named(values))
+                    if i.checked_sub(1).map(|p| chars[p]) != Some(':')
+                        && chars
+                            .get(i + 1)
+                            .is_some_and(|n| n.is_ascii_alphabetic() || *n == '_') =>
+                {
+                    let len = chars[i + 1..]
+                        .iter()
+                        .take_while(|n| n.is_ascii_alphanumeric() || **n == '_')
+                        .count();
+                    let name = chars[i + 1..i + 1 + len].iter().collect::<String>();
+                    let value = values
+                        .get(&name)
+                        .ok_or_else(|| format!("missing value for parameter :{}", name))?;
+                    res.push_str(&literal(value, dialect));
+                    i += 1 + len;
+                }
+                _ => {
+                    res.push(c);
+                    i += 1;
+                }
+            }
+        }
+
+        if let Params::Positional(values) = params {
+            if next != values.len() {
+                return Err(format!("expected {} parameters, got {}", next, values.len()).into());
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Index right after the quoted section starting at `start`, doubled quotes don't end it
+fn quoted_end(chars: &[char], start: usize, dialect: Dialect) -> usize {
+    let quote = chars[start];
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if quote == '\'' && dialect == Dialect::Databend => i += 2,
+            c if c == quote && chars.get(i + 1) == Some(&quote) => i += 2,
+            c if c == quote => return i + 1,
+            _ => i += 1,
+        }
+    }
+    chars.len()
+}
+
+/// Index right after the `$tag$ .. $tag$` string starting at `start`, `None` when the `$`
+/// doesn't open one, e.g. a `$1` parameter
+fn dollar_quoted_end(chars: &[char], start: usize) -> Option<usize> {
+    let tag_len = chars[start + 1..]
+        .iter()
+        .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+        .count();
+    let close = start + 1 + tag_len;
+    if chars.get(close) != Some(&'$') || chars.get(start + 1).is_some_and(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let tag = &chars[start..=close];
+    Some(
+        (close + 1..chars.len())
+            .find(|j| chars[*j..].starts_with(tag))
+            .map_or(chars.len(), |j| j + tag.len()),
+    )
+}
+
+/// SQL literal of a JSON value, arrays and objects are passed as JSON strings
+pub(super) fn literal(value: &Value, dialect: Dialect) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Bool(b) => if *b { "TRUE" } else { "FALSE" }.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => dialect.quote(s),
+        Value::Array(_) | Value::Object(_) => dialect.quote(&value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn bind(sql: &str, params: Value, dialect: Dialect) -> Result<String, Box<dyn Error>> {
+        CustomSql {
+            sql: sql.to_string(),
+            params: Some(serde_json::from_value(params).unwrap()),
+        }
+        .bind(dialect)
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(
+            bind(
+                "SELECT * FROM t WHERE a = ? AND b = ?",
+                json!([1, "it's"]),
+                Dialect::Postgres
+            )
+            .unwrap(),
+            "SELECT * FROM t WHERE a = 1 AND b = 'it''s'"
+        );
+        assert_eq!(
+            bind(
+                "SELECT :a::text, :b",
+                json!({"a": "x", "b": null}),
+                Dialect::Postgres
+            )
+            .unwrap(),
+            "SELECT 'x'::text, NULL"
+        );
+        assert_eq!(
+            bind("SELECT ?", json!(["a\\b"]), Dialect::Databend).unwrap(),
+            "SELECT 'a\\\\b'"
+        );
+        assert!(bind("SELECT ?, ?", json!([1]), Dialect::Sqlite).is_err());
+        assert!(bind("SELECT ?", json!([1, 2]), Dialect::Sqlite).is_err());
+        assert!(bind("SELECT :a", json!({"b": 1}), Dialect::Sqlite).is_err());
+    }
+
+    #[test]
+    fn quoted_sections() {
+        assert_eq!(
+            bind(
+                "SELECT '?', \"a?\", 'it''s ?' -- ?\n, ? /* ? */",
+                json!([1]),
+                Dialect::Sqlite
+            )
+            .unwrap(),
+            "SELECT '?', \"a?\", 'it''s ?' -- ?\n, 1 /* ? */"
+        );
+        assert_eq!(
+            bind("SELECT 'a\\' ?', ?", json!([1]), Dialect::Databend).unwrap(),
+            "SELECT 'a\\' ?', 1"
+        );
+    }
+
+    #[test]
+    fn dollar_quoted() {
+        assert_eq!(
+            bind("SELECT $$ ? :a $$, ?", json!([1]), Dialect::Postgres).unwrap(),
+            "SELECT $$ ? :a $$, 1"
+        );
+        assert_eq!(
+            bind(
+                "SELECT $fn$ it's $$ ? $fn$, :a",
+                json!({"a": 2}),
+                Dialect::Postgres
+            )
+            .unwrap(),
+            "SELECT $fn$ it's $$ ? $fn$, 2"
+        );
+        // positional parameters of the backend are no dollar quotes
+        assert_eq!(
+            bind("SELECT $1, ?", json!([1]), Dialect::Postgres).unwrap(),
+            "SELECT $1, 1"
+        );
+    }
+
+    #[test]
+    fn jsonb_operators() {
+        assert_eq!(
+            bind(
+                "SELECT * FROM t WHERE d ?| array['a'] AND d ?& array['b'] AND id = ?",
+                json!([1]),
+                Dialect::Postgres
+            )
+            .unwrap(),
+            "SELECT * FROM t WHERE d ?| array['a'] AND d ?& array['b'] AND id = 1"
+        );
+    }
+}
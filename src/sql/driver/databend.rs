@@ -1,84 +1,279 @@
-use std::{error::Error, sync::Arc};
+use std::{
+    error::Error,
+    fmt::Debug,
+    future::Future,
+    iter::Peekable,
+    str::Chars,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
 
-use async_graphql::futures_util::{future::BoxFuture, FutureExt, StreamExt};
+use async_graphql::futures_util::{
+    future::{self, BoxFuture},
+    stream::BoxStream,
+    FutureExt, StreamExt,
+};
 use chrono::{NaiveDate, NaiveDateTime};
+use databend_driver::{DataType, Row, RowWithProgress, Schema};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
-use super::model::{Conn, ConnParams, Pool, DB};
+use super::{
+    error::SqlError,
+    migration::Dialect,
+    model::{
+        read_to_end, ColumnInfo, Conn, ConnParams, ConnPoolConfig, KeyInfo, Pool, RowStream, DB,
+    },
+};
 
+/// A connection checked out of a [`DatabendPool`], returned to it on drop unless a statement
+/// failed or was dropped midway, or a transaction it began is still open
 pub struct DatabendConn {
     pub conn: Arc<Box<dyn databend_driver::Connection>>,
+    created: Instant,
+    pool: DatabendPool,
+    _permit: OwnedSemaphorePermit,
+    /// set while a statement runs or its rows are streamed, and kept once one fails
+    dirty: Arc<AtomicBool>,
+    in_tx: AtomicBool,
 }
+
+impl DatabendConn {
+    fn new(
+        conn: Arc<Box<dyn databend_driver::Connection>>,
+        created: Instant,
+        pool: DatabendPool,
+        permit: OwnedSemaphorePermit,
+    ) -> Self {
+        Self {
+            conn,
+            created,
+            pool,
+            _permit: permit,
+            dirty: Arc::new(AtomicBool::new(false)),
+            in_tx: AtomicBool::new(false),
+        }
+    }
+
+    async fn tracked<T>(
+        &self,
+        f: impl Future<Output = Result<T, SqlError>>,
+    ) -> Result<T, SqlError> {
+        let dirty = self.dirty.swap(true, Ordering::SeqCst);
+        let res = f.await;
+        if res.is_ok() && !dirty {
+            self.dirty.store(false, Ordering::SeqCst);
+        }
+        res
+    }
+
+    /// Rows of the query with the schema typing them, progress updates are skipped
+    async fn typed_rows(
+        &self,
+        sql: &str,
+    ) -> Result<(Schema, BoxStream<'static, Result<Row, SqlError>>), SqlError> {
+        let (schema, rows) = self
+            .conn
+            .query_iter_ext(sql)
+            .await
+            .map_err(SqlError::databend)?;
+        let rows = rows.filter_map(|row| {
+            future::ready(match row {
+                Ok(RowWithProgress::Row(row)) => Some(Ok(row)),
+                Ok(RowWithProgress::Progress(_)) => None,
+                Err(e) => Some(Err(SqlError::databend(e))),
+            })
+        });
+        Ok((schema, rows.boxed()))
+    }
+}
+
+impl Drop for DatabendConn {
+    fn drop(&mut self) {
+        if self.pool.permits.is_closed()
+            || self.created.elapsed() >= self.pool.config.max_lifetime
+            || self.dirty.load(Ordering::SeqCst)
+            || self.in_tx.load(Ordering::SeqCst)
+        {
+            return;
+        }
+        self.pool.idle.lock().unwrap().push(IdleConn {
+            conn: self.conn.clone(),
+            created: self.created,
+            since: Instant::now(),
+        });
+    }
+}
+
 impl Conn for DatabendConn {
-    fn exec<R>(&self, request: R) -> BoxFuture<Result<i64, Box<dyn Error>>>
+    fn exec<R>(&self, request: R) -> BoxFuture<Result<i64, SqlError>>
     where
         R: ToString + Send,
     {
         let req = request.to_string();
-        async move { self.conn.exec(&req.to_string()).await.map_err(|e| e.into()) }.boxed()
+        self.tracked(async move {
+            let res = self.conn.exec(&req).await.map_err(SqlError::databend)?;
+            match req.trim() {
+                "BEGIN" => self.in_tx.store(true, Ordering::SeqCst),
+                "COMMIT" | "ROLLBACK" => self.in_tx.store(false, Ordering::SeqCst),
+                _ => {}
+            }
+            Ok(res)
+        })
+        .boxed()
     }
 
-    fn all(
+    fn custom_query(
         &self,
-        request: riwaq::sql::Select<super::model::SQLFilter>,
-    ) -> BoxFuture<Result<Vec<serde_json::Value>, Box<dyn Error>>> {
-        async move {
-            let mut rows = self.conn.query_iter(&request.to_string()).await.unwrap();
+        request: String,
+    ) -> BoxFuture<Result<Vec<Vec<serde_json::Value>>, SqlError>> {
+        self.tracked(async move {
+            let (schema, mut rows) = self.typed_rows(&request).await?;
             let mut res = vec![];
             while let Some(row) = rows.next().await {
-                let row = match row {
-                    Ok(r) => r,
-                    Err(_) => continue,
-                };
-                let mut r = serde_json::Map::new();
-                for (col, value) in request.cols.clone().into_iter().zip(row.values()) {
-                    r.insert(col.to_string(), databend_to_serde(value.clone()));
-                }
-                res.push(serde_json::Value::Object(r));
+                let row = row?;
+                res.push(
+                    schema
+                        .fields()
+                        .iter()
+                        .zip(row.values())
+                        .map(|(field, v)| databend_to_serde(&field.data_type, v.to_owned()))
+                        .collect::<Result<Vec<serde_json::Value>, SqlError>>()?,
+                );
             }
             Ok(res)
+        })
+        .boxed()
+    }
+
+    /// Pages of the result are fetched as the stream is read, the connection is only reused
+    /// once the stream was read to its end
+    fn stream(&self, request: String) -> BoxFuture<Result<RowStream, SqlError>> {
+        async move {
+            self.dirty.store(true, Ordering::SeqCst);
+            let (schema, rows) = self.typed_rows(&request).await?;
+            let rows = rows
+                .map(move |row| {
+                    schema
+                        .fields()
+                        .iter()
+                        .zip(row?.values())
+                        .map(|(field, v)| databend_to_serde(&field.data_type, v.to_owned()))
+                        .collect()
+                })
+                .boxed();
+            Ok(read_to_end(rows, self.dirty.clone()))
         }
         .boxed()
     }
 
-    fn custom_query(
+    fn discard(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    fn columns(
         &self,
-        request: String,
-    ) -> BoxFuture<Result<Vec<Vec<serde_json::Value>>, Box<dyn Error>>> {
-        async move {
-            let mut rows = self.conn.query_iter(&request).await.unwrap();
+        org: String,
+        table: String,
+    ) -> BoxFuture<Result<Option<Vec<ColumnInfo>>, SqlError>> {
+        self.tracked(async move {
+            let mut rows = self
+                .conn
+                .query_iter(&format!(
+                    "SELECT name, type, is_nullable FROM system.columns WHERE database = '{}' AND table = '{}';",
+                    org.replace('\'', "''"),
+                    table.replace('\'', "''")
+                ))
+                .await
+                .map_err(SqlError::databend)?;
             let mut res = vec![];
             while let Some(row) = rows.next().await {
-                let row = match row {
-                    Ok(r) => r,
-                    Err(_) => continue,
+                let values = row.map_err(SqlError::databend)?.values().to_owned();
+                let text = |i: usize| match values.get(i) {
+                    Some(databend_driver::Value::String(v)) => v.to_owned(),
+                    _ => String::new(),
                 };
-                res.push(
-                    row.values()
-                        .into_iter()
-                        .map(|v| databend_to_serde(v.to_owned()))
-                        .collect(),
-                );
+                res.push(ColumnInfo {
+                    name: text(0),
+                    ty: text(1),
+                    nullable: text(2) == "YES",
+                });
             }
-            Ok(res)
-        }
+            Ok(if res.is_empty() { None } else { Some(res) })
+        })
+        .boxed()
+    }
+
+    /// Databend has no keys or indexes to declare besides the cluster key
+    fn keys(&self, org: String, table: String) -> BoxFuture<Result<KeyInfo, SqlError>> {
+        self.tracked(async move {
+            let mut rows = self
+                .conn
+                .query_iter(&format!(
+                    "SELECT cluster_by FROM system.tables WHERE database = '{}' AND name = '{}';",
+                    org.replace('\'', "''"),
+                    table.replace('\'', "''")
+                ))
+                .await
+                .map_err(SqlError::databend)?;
+            let mut cluster_by = None;
+            while let Some(row) = rows.next().await {
+                if let Some(databend_driver::Value::String(v)) =
+                    row.map_err(SqlError::databend)?.values().first()
+                {
+                    cluster_by = Some(v.to_owned()).filter(|v| !v.is_empty());
+                }
+            }
+            Ok(KeyInfo {
+                cluster_by,
+                indexes: vec![],
+            })
+        })
         .boxed()
     }
+
+    fn dialect(&self) -> Dialect {
+        Dialect::Databend
+    }
 }
 
 pub struct DatabendConnParams {
     conn_str: String,
+    config: ConnPoolConfig,
 }
 impl DatabendConnParams {
-    pub fn new(conn_str: String) -> Self {
-        Self { conn_str }
+    pub fn new(conn_str: String, config: ConnPoolConfig) -> Self {
+        Self { conn_str, config }
     }
 }
 
 impl ConnParams for DatabendConnParams {}
 
-#[derive(Clone, Debug)]
+struct IdleConn {
+    conn: Arc<Box<dyn databend_driver::Connection>>,
+    created: Instant,
+    since: Instant,
+}
+
+/// Bounded pool of connections reused across handler calls
+#[derive(Clone)]
 pub struct DatabendPool {
     pub conn_str: String,
+    config: ConnPoolConfig,
+    idle: Arc<Mutex<Vec<IdleConn>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl Debug for DatabendPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DatabendPool")
+            .field("conn_str", &self.conn_str)
+            .field("config", &self.config)
+            .field("idle", &self.idle.lock().unwrap().len())
+            .finish()
+    }
 }
 
 impl Pool for DatabendPool {
@@ -86,19 +281,63 @@ impl Pool for DatabendPool {
 
     fn conn(&self) -> BoxFuture<Result<DatabendConn, Box<dyn Error>>> {
         async {
-            Ok(DatabendConn {
-                conn: Arc::new(
-                    databend_driver::Client::new(self.conn_str.to_owned())
-                        .get_conn()
-                        .await?,
-                ),
-            })
+            let permit = self
+                .permits
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(|_| "database pool is disconnected")?;
+
+            let config = &self.config;
+            self.idle.lock().unwrap().retain(|c| {
+                c.since.elapsed() < config.idle_timeout && c.created.elapsed() < config.max_lifetime
+            });
+
+            // most recently used first, so surplus connections idle out
+            loop {
+                let idle = self.idle.lock().unwrap().pop();
+                let idle = match idle {
+                    Some(idle) => idle,
+                    None => break,
+                };
+                if idle.since.elapsed() >= config.check_after
+                    && idle.conn.exec("SELECT 1").await.is_err()
+                {
+                    continue;
+                }
+                return Ok(DatabendConn::new(
+                    idle.conn,
+                    idle.created,
+                    self.clone(),
+                    permit,
+                ));
+            }
+
+            let conn = databend_driver::Client::new(self.conn_str.to_owned())
+                .get_conn()
+                .await?;
+            Ok(DatabendConn::new(
+                Arc::new(conn),
+                Instant::now(),
+                self.clone(),
+                permit,
+            ))
         }
         .boxed()
     }
 
+    /// Closes idle connections and refuses new checkouts, busy ones close once released
     fn disconnect(&self) -> BoxFuture<Result<(), Box<dyn Error>>> {
-        async { Ok(()) }.boxed()
+        async {
+            self.permits.close();
+            self.idle.lock().unwrap().clear();
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn dialect(&self) -> Dialect {
+        Dialect::Databend
     }
 }
 
@@ -110,42 +349,364 @@ impl DB for Databend {
 
     fn init(params: DatabendConnParams) -> Result<DatabendPool, Box<dyn Error>> {
         Ok(DatabendPool {
-            conn_str: params.conn_str
+            conn_str: utc_dsn(&params.conn_str)?,
+            permits: Arc::new(Semaphore::new(params.config.max)),
+            idle: Arc::new(Mutex::new(vec![])),
+            config: params.config,
         })
     }
 }
 
-fn databend_to_serde(value: databend_driver::Value) -> serde_json::Value {
-    match value {
+/// Column values as JSON, the same for [`Conn::all`] and [`Conn::custom_query`]:
+/// - integers and floats are numbers, booleans booleans, strings strings
+/// - decimals are strings keeping their scale, e.g. `Decimal(10, 2)` `1.5` is `"1.50"`
+/// - timestamps are RFC 3339 strings with their offset, sessions are pinned to UTC so it is
+///   always `+00:00`, dates are `YYYY-MM-DD`
+/// - arrays are arrays, tuples arrays of their fields and maps objects keyed by the keys' text,
+///   their values typed the same way
+/// - variant (JSON) values are the JSON they hold
+/// - binary is the hex text databend renders it to, other types keep their text as well
+///
+/// Timestamps and dates chrono can't represent fail the query rather than read as null.
+fn databend_to_serde(
+    ty: &DataType,
+    value: databend_driver::Value,
+) -> Result<serde_json::Value, SqlError> {
+    Ok(match value {
         databend_driver::Value::Null => serde_json::Value::Null,
-        databend_driver::Value::Boolean(v) => serde_json::to_value(v).unwrap(),
-        databend_driver::Value::String(v) => serde_json::to_value(v).unwrap(),
+        databend_driver::Value::Boolean(v) => serde_json::Value::from(v),
+        // nested and semi-structured values come as the text databend renders them to
+        databend_driver::Value::String(v) => match not_null(ty) {
+            DataType::Variant => serde_json::from_str(&v).unwrap_or(serde_json::Value::String(v)),
+            DataType::Array(_) | DataType::Map(_) | DataType::Tuple(_) => match parse_text(&v) {
+                Some(text) => typed_text(ty, text),
+                None => serde_json::Value::from(v),
+            },
+            _ => serde_json::Value::from(v),
+        },
         databend_driver::Value::Number(v) => match v {
-            databend_driver::NumberValue::Int8(n) => serde_json::to_value(n).unwrap(),
-            databend_driver::NumberValue::Int16(n) => serde_json::to_value(n).unwrap(),
-            databend_driver::NumberValue::Int32(n) => serde_json::to_value(n).unwrap(),
-            databend_driver::NumberValue::Int64(n) => serde_json::to_value(n).unwrap(),
-            databend_driver::NumberValue::UInt8(n) => serde_json::to_value(n).unwrap(),
-            databend_driver::NumberValue::UInt16(n) => serde_json::to_value(n).unwrap(),
-            databend_driver::NumberValue::UInt32(n) => serde_json::to_value(n).unwrap(),
-            databend_driver::NumberValue::UInt64(n) => serde_json::to_value(n).unwrap(),
-            databend_driver::NumberValue::Float32(n) => serde_json::to_value(n).unwrap(),
-            databend_driver::NumberValue::Float64(n) => serde_json::to_value(n).unwrap(),
-            databend_driver::NumberValue::Decimal128(n, _) => serde_json::to_value(n).unwrap(),
-            databend_driver::NumberValue::Decimal256(n, _) => {
-                serde_json::to_value(n.as_i128()).unwrap()
+            databend_driver::NumberValue::Int8(n) => serde_json::Value::from(n),
+            databend_driver::NumberValue::Int16(n) => serde_json::Value::from(n),
+            databend_driver::NumberValue::Int32(n) => serde_json::Value::from(n),
+            databend_driver::NumberValue::Int64(n) => serde_json::Value::from(n),
+            databend_driver::NumberValue::UInt8(n) => serde_json::Value::from(n),
+            databend_driver::NumberValue::UInt16(n) => serde_json::Value::from(n),
+            databend_driver::NumberValue::UInt32(n) => serde_json::Value::from(n),
+            databend_driver::NumberValue::UInt64(n) => serde_json::Value::from(n),
+            databend_driver::NumberValue::Float32(n) => serde_json::Value::from(n),
+            databend_driver::NumberValue::Float64(n) => serde_json::Value::from(n),
+            databend_driver::NumberValue::Decimal128(n, size) => {
+                serde_json::Value::from(decimal(n.to_string(), size.scale))
+            }
+            databend_driver::NumberValue::Decimal256(n, size) => {
+                serde_json::Value::from(decimal(n.to_string(), size.scale))
             }
         },
-        databend_driver::Value::Timestamp(_) => serde_json::to_value(
+        databend_driver::Value::Timestamp(ts) => timestamp(
             NaiveDateTime::try_from(value)
-                .unwrap()
-                .format("%Y-%m-%dT%H:%M:%S%.f+00:00")
+                .map_err(|e| format!("timestamp {} can't be read: {}", ts, e))?,
+        ),
+        databend_driver::Value::Date(days) => serde_json::Value::from(
+            NaiveDate::try_from(value)
+                .map_err(|e| format!("date {} can't be read: {}", days, e))?
+                .format("%F")
                 .to_string(),
-        )
-        .unwrap(),
-        databend_driver::Value::Date(_) => {
-            serde_json::to_value(NaiveDate::try_from(value).unwrap().format("%F").to_string())
-                .unwrap()
+        ),
+    })
+}
+
+fn not_null(ty: &DataType) -> &DataType {
+    match ty {
+        DataType::Nullable(ty) => not_null(ty),
+        ty => ty,
+    }
+}
+
+/// `digits` of an unscaled decimal with the point put back, e.g. `-1234` of scale 2 is `-12.34`
+fn decimal(digits: String, scale: u8) -> String {
+    let scale = scale as usize;
+    if scale == 0 {
+        return digits;
+    }
+    let (sign, digits) = match digits.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", digits.as_str()),
+    };
+    let digits = format!("{:0>width$}", digits, width = scale + 1);
+    let (int, frac) = digits.split_at(digits.len() - scale);
+    format!("{}{}.{}", sign, int, frac)
+}
+
+fn timestamp(t: NaiveDateTime) -> serde_json::Value {
+    serde_json::Value::from(t.format("%Y-%m-%dT%H:%M:%S%.6f+00:00").to_string())
+}
+
+/// Value as databend renders nested ones, `[..]` arrays, `{k: v}` maps, `(..)` tuples and
+/// quoted strings, scalars are kept as their text
+enum Text {
+    Bare(String),
+    Quoted(String),
+    List(Vec<Text>),
+    Map(Vec<(Text, Text)>),
+    Tuple(Vec<Text>),
+}
+
+fn parse_text(s: &str) -> Option<Text> {
+    let mut chars = s.chars().peekable();
+    let text = text_value(&mut chars)?;
+    skip_ws(&mut chars);
+    chars.next().is_none().then_some(text)
+}
+
+fn skip_ws(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn text_value(chars: &mut Peekable<Chars>) -> Option<Text> {
+    skip_ws(chars);
+    match *chars.peek()? {
+        '[' => {
+            chars.next();
+            Some(Text::List(text_items(chars, ']')?))
         }
+        '(' => {
+            chars.next();
+            Some(Text::Tuple(text_items(chars, ')')?))
+        }
+        '{' => {
+            chars.next();
+            let mut pairs = vec![];
+            skip_ws(chars);
+            if chars.next_if_eq(&'}').is_some() {
+                return Some(Text::Map(pairs));
+            }
+            loop {
+                let key = text_value(chars)?;
+                skip_ws(chars);
+                chars.next_if_eq(&':')?;
+                pairs.push((key, text_value(chars)?));
+                skip_ws(chars);
+                match chars.next()? {
+                    ',' => continue,
+                    '}' => return Some(Text::Map(pairs)),
+                    _ => return None,
+                }
+            }
+        }
+        q @ ('\'' | '"') => {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next()? {
+                    '\\' => match chars.next()? {
+                        'n' => s.push('\n'),
+                        'r' => s.push('\r'),
+                        't' => s.push('\t'),
+                        '0' => s.push('\0'),
+                        c => s.push(c),
+                    },
+                    c if c == q => match chars.next_if_eq(&q) {
+                        Some(c) => s.push(c),
+                        None => return Some(Text::Quoted(s)),
+                    },
+                    c => s.push(c),
+                }
+            }
+        }
+        _ => {
+            let mut s = String::new();
+            while let Some(c) = chars.next_if(|c| !",:]})".contains(*c)) {
+                s.push(c);
+            }
+            Some(Text::Bare(s.trim().to_string()))
+        }
+    }
+}
+
+fn text_items(chars: &mut Peekable<Chars>, close: char) -> Option<Vec<Text>> {
+    let mut items = vec![];
+    skip_ws(chars);
+    if chars.next_if_eq(&close).is_some() {
+        return Some(items);
+    }
+    loop {
+        items.push(text_value(chars)?);
+        skip_ws(chars);
+        match chars.next()? {
+            ',' => continue,
+            c if c == close => return Some(items),
+            _ => return None,
+        }
+    }
+}
+
+/// `text` as JSON typed by the column type, as [`databend_to_serde`] does for top level values
+fn typed_text(ty: &DataType, text: Text) -> serde_json::Value {
+    match (not_null(ty), text) {
+        (_, Text::Bare(v)) if v.eq_ignore_ascii_case("null") => serde_json::Value::Null,
+        (DataType::Array(ty), Text::List(items)) => {
+            serde_json::Value::Array(items.into_iter().map(|item| typed_text(ty, item)).collect())
+        }
+        (DataType::Tuple(tys), Text::Tuple(items)) => serde_json::Value::Array(
+            tys.iter()
+                .zip(items)
+                .map(|(ty, item)| typed_text(ty, item))
+                .collect(),
+        ),
+        // a map's type is the tuple of its key and value types
+        (DataType::Map(ty), Text::Map(pairs)) => {
+            let value_ty = match not_null(ty) {
+                DataType::Tuple(tys) => tys.get(1).unwrap_or(&DataType::String),
+                _ => &DataType::String,
+            };
+            serde_json::Value::Object(
+                pairs
+                    .into_iter()
+                    .map(|(key, value)| (key_text(key), typed_text(value_ty, value)))
+                    .collect(),
+            )
+        }
+        (DataType::Variant, Text::Bare(v) | Text::Quoted(v)) => {
+            serde_json::from_str(&v).unwrap_or(serde_json::Value::String(v))
+        }
+        (DataType::Boolean, Text::Bare(v)) => {
+            serde_json::Value::Bool(v == "1" || v.eq_ignore_ascii_case("true"))
+        }
+        (DataType::Number(_), Text::Bare(v)) => serde_json::from_str::<serde_json::Number>(&v)
+            .map_or(serde_json::Value::String(v), serde_json::Value::Number),
+        (DataType::Timestamp, Text::Bare(v) | Text::Quoted(v)) => {
+            NaiveDateTime::parse_from_str(&v, "%Y-%m-%d %H:%M:%S%.f")
+                .map_or(serde_json::Value::String(v), timestamp)
+        }
+        // decimals are rendered with their scale already
+        (_, text) => untyped(text),
+    }
+}
+
+fn untyped(text: Text) -> serde_json::Value {
+    match text {
+        Text::Bare(v) | Text::Quoted(v) => serde_json::Value::String(v),
+        Text::List(items) | Text::Tuple(items) => {
+            serde_json::Value::Array(items.into_iter().map(untyped).collect())
+        }
+        Text::Map(pairs) => serde_json::Value::Object(
+            pairs
+                .into_iter()
+                .map(|(key, value)| (key_text(key), untyped(value)))
+                .collect(),
+        ),
+    }
+}
+
+fn key_text(key: Text) -> String {
+    match untyped(key) {
+        serde_json::Value::String(key) => key,
+        key => key.to_string(),
+    }
+}
+
+/// `url` with the session timezone set to UTC, databend renders timestamps in the session's
+/// timezone while the driver reads them as UTC. Another `timezone=` would shift every
+/// timestamp read, the url is refused instead.
+fn utc_dsn(url: &str) -> Result<String, String> {
+    let (base, query) = url.split_once('?').unwrap_or((url, ""));
+    let mut params = vec![];
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        match param.split_once('=') {
+            Some(("timezone", tz)) if !tz.eq_ignore_ascii_case("UTC") => {
+                return Err(format!(
+                    "databend url: timezone={} is not supported, sessions are pinned to UTC",
+                    tz
+                ))
+            }
+            Some(("timezone", _)) => {}
+            _ => params.push(param),
+        }
+    }
+    params.push("timezone=UTC");
+    Ok(format!("{}?{}", base, params.join("&")))
+}
+
+#[cfg(test)]
+mod tests {
+    use databend_client::response::SchemaField;
+    use databend_driver::Field;
+    use serde_json::json;
+
+    use super::*;
+
+    /// Type as the server names it in a result schema
+    fn ty(name: &str) -> DataType {
+        Field::try_from(SchemaField {
+            name: String::new(),
+            data_type: name.to_string(),
+        })
+        .unwrap()
+        .data_type
+    }
+
+    fn typed(ty: DataType, s: &str) -> serde_json::Value {
+        typed_text(&ty, parse_text(s).unwrap())
+    }
+
+    #[test]
+    fn arrays() {
+        let ints = ty("Array(Int64)");
+        assert_eq!(typed(ints.clone(), "[1, 2, 3]"), json!([1, 2, 3]));
+        assert_eq!(typed(ints, "[]"), json!([]));
+        let strings = ty("Array(Nullable(String))");
+        assert_eq!(
+            typed(strings, r#"['a, b', 'it''s', "q\"", NULL]"#),
+            json!(["a, b", "it's", "q\"", null])
+        );
+        let nested = ty("Array(Array(Boolean))");
+        assert_eq!(
+            typed(nested, "[[1, 0], [true]]"),
+            json!([[true, false], [true]])
+        );
+    }
+
+    #[test]
+    fn tuples_and_maps() {
+        let tuple = ty("Tuple(Int64, String, Timestamp)");
+        assert_eq!(
+            typed(tuple, "(1, 'a', '2024-01-02 03:04:05.000000')"),
+            json!([1, "a", "2024-01-02T03:04:05.000000+00:00"])
+        );
+        let map = ty("Map(String, Int64)");
+        assert_eq!(
+            typed(map.clone(), "{'a': 1, 'b': NULL}"),
+            json!({"a": 1, "b": null})
+        );
+        assert_eq!(typed(map, "{}"), json!({}));
+        let variants = ty("Array(Variant)");
+        assert_eq!(
+            typed(variants, r#"['{"k":[1]}', '2']"#),
+            json!([{"k": [1]}, 2])
+        );
+    }
+
+    #[test]
+    fn unparsed_text() {
+        assert!(parse_text("[1, 2").is_none());
+        assert!(parse_text("{'a' 1}").is_none());
+        assert!(parse_text("[1] 2").is_none());
+        // values that don't fit their type keep their text
+        assert_eq!(typed(ty("Array(Int64)"), "[x]"), json!(["x"]));
+        let stamps = ty("Array(Timestamp)");
+        assert_eq!(typed(stamps, "['infinity']"), json!(["infinity"]));
+    }
+
+    #[test]
+    fn dsn_timezone() {
+        assert_eq!(
+            utc_dsn("databend://u:p@host:8000/db").unwrap(),
+            "databend://u:p@host:8000/db?timezone=UTC"
+        );
+        assert_eq!(
+            utc_dsn("databend://host/db?sslmode=disable&timezone=utc").unwrap(),
+            "databend://host/db?sslmode=disable&timezone=UTC"
+        );
+        assert!(utc_dsn("databend://host/db?timezone=Asia/Tokyo").is_err());
     }
 }
@@ -0,0 +1,98 @@
+use std::{error::Error, fmt::Display};
+
+use databend_client::error::Error as ApiError;
+use serde::Serialize;
+
+/// Error of a database call, `code` is the backend's own code when it reports one: a databend
+/// error code, a postgres SQLSTATE or a sqlite extended result code
+#[derive(Debug, Clone, Serialize)]
+pub struct SqlError {
+    pub code: Option<String>,
+    pub msg: String,
+}
+
+impl SqlError {
+    pub fn new(msg: impl Into<String>) -> Self {
+        Self {
+            code: None,
+            msg: msg.into(),
+        }
+    }
+
+    /// Databend's code comes with the errors of the query api, other driver errors have none
+    pub fn databend(e: databend_driver::Error) -> Self {
+        let code = match &e {
+            databend_driver::Error::Api(
+                ApiError::InvalidResponse(err) | ApiError::InvalidPage(err),
+            ) => Some(err.code.to_string()),
+            _ => None,
+        };
+        Self {
+            code,
+            msg: e.to_string(),
+        }
+    }
+}
+
+impl Display for SqlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.code {
+            Some(code) => write!(f, "{} (code {})", self.msg, code),
+            None => write!(f, "{}", self.msg),
+        }
+    }
+}
+
+impl Error for SqlError {}
+
+impl From<String> for SqlError {
+    fn from(msg: String) -> Self {
+        Self::new(msg)
+    }
+}
+
+impl From<&str> for SqlError {
+    fn from(msg: &str) -> Self {
+        Self::new(msg)
+    }
+}
+
+impl From<serde_json::Error> for SqlError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::new(e.to_string())
+    }
+}
+
+impl From<Box<dyn Error>> for SqlError {
+    fn from(e: Box<dyn Error>) -> Self {
+        match e.downcast::<SqlError>() {
+            Ok(e) => *e,
+            Err(e) => Self::new(e.to_string()),
+        }
+    }
+}
+
+impl From<tokio_postgres::Error> for SqlError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        Self {
+            code: e.code().map(|c| c.code().to_string()),
+            msg: e
+                .as_db_error()
+                .map_or_else(|| e.to_string(), |db| db.message().to_string()),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for SqlError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self {
+            code: match &e {
+                rusqlite::Error::SqliteFailure(failure, _) => {
+                    Some(failure.extended_code.to_string())
+                }
+                _ => None,
+            },
+            msg: e.to_string(),
+        }
+    }
+}
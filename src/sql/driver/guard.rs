@@ -0,0 +1,481 @@
+use std::{collections::HashSet, error::Error, ops::ControlFlow};
+
+use sqlparser::{
+    ast::{
+        AlterTableOperation, Expr, Ident, ObjectName, ObjectType, Query, SetExpr, Statement,
+        TableFactor, TableWithJoins, Visit, Visitor,
+    },
+    dialect::{GenericDialect, PostgreSqlDialect, SQLiteDialect},
+    parser::Parser,
+};
+
+use crate::state::org_var;
+
+use super::migration::Dialect;
+
+/// Scalar, aggregate and window functions custom SQL may call, an org can allow more with a
+/// comma separated `DB_SQL_FUNCTIONS`
+const FUNCTIONS: [&str; 91] = [
+    "abs",
+    "array_agg",
+    "avg",
+    "bool_and",
+    "bool_or",
+    "ceil",
+    "ceiling",
+    "char_length",
+    "character_length",
+    "coalesce",
+    "concat",
+    "concat_ws",
+    "count",
+    "cume_dist",
+    "current_date",
+    "current_time",
+    "current_timestamp",
+    "date",
+    "date_part",
+    "date_trunc",
+    "datetime",
+    "dense_rank",
+    "exp",
+    "first_value",
+    "floor",
+    "gen_random_uuid",
+    "greatest",
+    "group_concat",
+    "if",
+    "ifnull",
+    "iif",
+    "instr",
+    "json_agg",
+    "json_array",
+    "json_build_array",
+    "json_build_object",
+    "json_extract",
+    "json_object",
+    "jsonb_agg",
+    "jsonb_build_array",
+    "jsonb_build_object",
+    "julianday",
+    "lag",
+    "last_value",
+    "lead",
+    "least",
+    "left",
+    "length",
+    "ln",
+    "localtime",
+    "localtimestamp",
+    "log",
+    "lower",
+    "lpad",
+    "ltrim",
+    "max",
+    "md5",
+    "min",
+    "mod",
+    "now",
+    "nth_value",
+    "ntile",
+    "nullif",
+    "percent_rank",
+    "pow",
+    "power",
+    "random",
+    "rank",
+    "regexp_replace",
+    "repeat",
+    "replace",
+    "reverse",
+    "right",
+    "round",
+    "row_number",
+    "rpad",
+    "rtrim",
+    "sign",
+    "split_part",
+    "sqrt",
+    "starts_with",
+    "strftime",
+    "string_agg",
+    "strpos",
+    "substr",
+    "sum",
+    "to_char",
+    "to_date",
+    "to_timestamp",
+    "trunc",
+    "upper",
+];
+
+/// Table functions custom SQL may read from, they only generate rows
+const TABLE_FUNCTIONS: [&str; 3] = ["generate_series", "numbers", "unnest"];
+
+/// Refuses custom SQL touching anything but the org's own database. Statements are parsed
+/// and only queries, DML and table, view and index DDL are let through; tables must be
+/// qualified with the org (unqualified ones resolve to the shared default database, except
+/// on sqlite where the connection only sees the org's) and only allow-listed functions and
+/// table functions may be called. Anything that doesn't parse is refused.
+pub fn check_tenant(sql: &str, org: &str, dialect: Dialect) -> Result<(), Box<dyn Error>> {
+    let statements = match dialect {
+        Dialect::Databend => Parser::parse_sql(&GenericDialect {}, sql),
+        Dialect::Postgres => Parser::parse_sql(&PostgreSqlDialect {}, sql),
+        Dialect::Sqlite => Parser::parse_sql(&SQLiteDialect {}, sql),
+    }
+    .map_err(|e| format!("custom sql can't be checked: {}", e))?;
+    let mut guard = Guard {
+        org,
+        dialect,
+        functions: org_var("DB_SQL_FUNCTIONS", org)
+            .unwrap_or_default()
+            .split(',')
+            .map(|f| f.trim().to_lowercase())
+            .filter(|f| !f.is_empty())
+            .collect(),
+        ctes: vec![],
+    };
+    for stmt in &statements {
+        guard.statement(stmt)?;
+        if let ControlFlow::Break(e) = stmt.visit(&mut guard) {
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+struct Guard<'a> {
+    org: &'a str,
+    dialect: Dialect,
+    functions: HashSet<String>,
+    /// CTE names of the queries being visited, innermost last, unqualified reads of them stay
+    /// inside the statement
+    ctes: Vec<HashSet<String>>,
+}
+
+impl Guard<'_> {
+    /// Checks the statement kind and the objects it writes to or defines, what it reads is
+    /// left to the visitor
+    fn statement(&self, stmt: &Statement) -> Result<(), String> {
+        match stmt {
+            Statement::Query(_) => Ok(()),
+            Statement::Insert { table_name, .. } | Statement::Truncate { table_name, .. } => {
+                self.target(table_name)
+            }
+            Statement::Update { table, .. } => self.targets([table]),
+            Statement::Delete { from, .. } => self.targets(from),
+            Statement::CreateTable {
+                name, like, clone, ..
+            } => [Some(name), like.as_ref(), clone.as_ref()]
+                .into_iter()
+                .flatten()
+                .try_for_each(|t| self.target(t)),
+            Statement::CreateView { name, .. } => self.target(name),
+            Statement::CreateIndex {
+                name, table_name, ..
+            } => {
+                self.target(table_name)?;
+                name.iter().try_for_each(|n| self.local(n))
+            }
+            Statement::AlterTable {
+                name, operations, ..
+            } => {
+                self.target(name)?;
+                operations.iter().try_for_each(|op| match op {
+                    AlterTableOperation::RenameTable { table_name } => self.local(table_name),
+                    _ => Ok(()),
+                })
+            }
+            Statement::Drop {
+                object_type: ObjectType::Table | ObjectType::View | ObjectType::Index,
+                names,
+                ..
+            } => names.iter().try_for_each(|n| self.target(n)),
+            _ => Err(format!(
+                "'{}' statements are not allowed in custom sql",
+                stmt.to_string()
+                    .split_whitespace()
+                    .take(2)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )),
+        }
+    }
+
+    fn targets<'t>(
+        &self,
+        tables: impl IntoIterator<Item = &'t TableWithJoins>,
+    ) -> Result<(), String> {
+        tables.into_iter().try_for_each(|t| match &t.relation {
+            TableFactor::Table {
+                name, args: None, ..
+            } => self.target(name),
+            relation => Err(format!("can't write to '{}'", relation)),
+        })
+    }
+
+    /// A table written to or defined, CTE names don't count
+    fn target(&self, name: &ObjectName) -> Result<(), String> {
+        match name.0.as_slice() {
+            [db, _] if self.is_org(db) => Ok(()),
+            [_] if self.dialect == Dialect::Sqlite => Ok(()),
+            _ => Err(format!("table '{}' is outside org '{}'", name, self.org)),
+        }
+    }
+
+    /// Tables created by `SELECT ... INTO`, the queries nested in `body` are visited on their own
+    fn select_into(&self, body: &SetExpr) -> Result<(), String> {
+        match body {
+            SetExpr::Select(select) => match &select.into {
+                Some(into) => self.target(&into.name),
+                None => Ok(()),
+            },
+            SetExpr::SetOperation { left, right, .. } => {
+                self.select_into(left)?;
+                self.select_into(right)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn is_cte(&self, name: &Ident) -> bool {
+        let name = name.value.to_lowercase();
+        self.ctes.iter().any(|scope| scope.contains(&name))
+    }
+
+    /// An index or a rename target, unqualified ones stay next to their table
+    fn local(&self, name: &ObjectName) -> Result<(), String> {
+        match name.0.as_slice() {
+            [_] => Ok(()),
+            _ => self.target(name),
+        }
+    }
+
+    fn is_org(&self, ident: &Ident) -> bool {
+        match ident.quote_style {
+            Some(_) => ident.value == self.org,
+            None => ident.value.eq_ignore_ascii_case(self.org),
+        }
+    }
+}
+
+impl Visitor for Guard<'_> {
+    type Break = String;
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<String> {
+        self.ctes.push(
+            query
+                .with
+                .iter()
+                .flat_map(|with| &with.cte_tables)
+                .map(|cte| cte.alias.name.value.to_lowercase())
+                .collect(),
+        );
+        self.select_into(&query.body)
+            .map_or_else(ControlFlow::Break, ControlFlow::Continue)
+    }
+
+    fn post_visit_query(&mut self, _query: &Query) -> ControlFlow<String> {
+        self.ctes.pop();
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_table_factor(&mut self, table: &TableFactor) -> ControlFlow<String> {
+        let checked = match table {
+            TableFactor::Table {
+                name, args: None, ..
+            } => match name.0.as_slice() {
+                [cte] if self.is_cte(cte) => Ok(()),
+                _ => self.target(name),
+            },
+            TableFactor::Table {
+                name,
+                args: Some(_),
+                ..
+            }
+            | TableFactor::Function { name, .. } => match name.0.as_slice() {
+                [f] if TABLE_FUNCTIONS.contains(&f.value.to_lowercase().as_str()) => Ok(()),
+                _ => Err(format!(
+                    "table function '{}' is not allowed in custom sql",
+                    name
+                )),
+            },
+            TableFactor::Derived { .. }
+            | TableFactor::NestedJoin { .. }
+            | TableFactor::UNNEST { .. }
+            | TableFactor::Pivot { .. }
+            | TableFactor::Unpivot { .. } => Ok(()),
+            _ => Err(format!("'{}' is not allowed in custom sql", table)),
+        };
+        checked.map_or_else(ControlFlow::Break, ControlFlow::Continue)
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<String> {
+        let checked = match expr {
+            Expr::Function(f) => match f.name.0.as_slice() {
+                [name]
+                    if FUNCTIONS.contains(&name.value.to_lowercase().as_str())
+                        || self.functions.contains(&name.value.to_lowercase()) =>
+                {
+                    Ok(())
+                }
+                _ => Err(format!(
+                    "function '{}' is not allowed in custom sql",
+                    f.name
+                )),
+            },
+            // db.table.column
+            Expr::CompoundIdentifier(parts) if parts.len() > 2 && !self.is_org(&parts[0]) => {
+                Err(format!(
+                    "'{}' is outside org '{}'",
+                    ObjectName(parts.clone()),
+                    self.org
+                ))
+            }
+            _ => Ok(()),
+        };
+        checked.map_or_else(ControlFlow::Break, ControlFlow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(sql: &str, dialect: Dialect) -> bool {
+        check_tenant(sql, "acme", dialect).is_ok()
+    }
+
+    #[test]
+    fn org_tables() {
+        assert!(allowed(
+            "SELECT u.id, count(*) FROM acme.users u JOIN acme.orders o ON o.user_id = u.id GROUP BY u.id",
+            Dialect::Postgres
+        ));
+        assert!(allowed(
+            "WITH recent AS (SELECT * FROM acme.orders WHERE created > now()) SELECT * FROM recent",
+            Dialect::Postgres
+        ));
+        assert!(allowed(
+            "INSERT INTO acme.users (id, name) VALUES ($1, 'a') RETURNING id",
+            Dialect::Postgres
+        ));
+        assert!(allowed(
+            "UPDATE acme.users SET name = upper(name) WHERE id IN (SELECT user_id FROM acme.orders)",
+            Dialect::Databend
+        ));
+        assert!(allowed("DELETE FROM users WHERE id = 1", Dialect::Sqlite));
+        assert!(allowed(
+            "SELECT * FROM generate_series(1, 10)",
+            Dialect::Postgres
+        ));
+    }
+
+    #[test]
+    fn other_databases() {
+        assert!(!allowed("SELECT * FROM other.users", Dialect::Postgres));
+        assert!(!allowed("SELECT * FROM users", Dialect::Postgres));
+        assert!(!allowed("SELECT * FROM users", Dialect::Databend));
+        assert!(!allowed(
+            "SELECT * FROM pg_catalog.pg_user",
+            Dialect::Postgres
+        ));
+        assert!(!allowed(
+            "SELECT other.users.id FROM acme.users",
+            Dialect::Postgres
+        ));
+        assert!(!allowed(
+            "WITH users AS (SELECT 1) DELETE FROM users",
+            Dialect::Postgres
+        ));
+        assert!(!allowed("SELECT * FROM other.users", Dialect::Sqlite));
+        assert!(!allowed(
+            "SELECT * INTO other.stolen FROM acme.users",
+            Dialect::Postgres
+        ));
+        assert!(!allowed(
+            "SELECT 1 UNION SELECT * INTO stolen FROM acme.users",
+            Dialect::Postgres
+        ));
+        assert!(allowed(
+            "SELECT * INTO acme.users_copy FROM acme.users",
+            Dialect::Postgres
+        ));
+        assert!(!allowed(
+            "INSERT INTO other.users SELECT * FROM acme.users",
+            Dialect::Databend
+        ));
+    }
+
+    #[test]
+    fn cte_scope() {
+        assert!(!allowed(
+            "WITH users AS (SELECT 1) SELECT 1; SELECT * FROM users",
+            Dialect::Postgres
+        ));
+        assert!(!allowed(
+            "SELECT * FROM (WITH users AS (SELECT 1) SELECT * FROM users) t, users",
+            Dialect::Postgres
+        ));
+        assert!(allowed(
+            "WITH users AS (SELECT * FROM acme.users) SELECT * FROM (SELECT * FROM users) t",
+            Dialect::Postgres
+        ));
+    }
+
+    #[test]
+    fn table_functions() {
+        assert!(!allowed(
+            "SELECT * FROM dblink('host=db', 'SELECT 1') AS t(a int)",
+            Dialect::Postgres
+        ));
+        assert!(!allowed(
+            "SELECT * FROM fuse_snapshot('other', 'users')",
+            Dialect::Databend
+        ));
+    }
+
+    #[test]
+    fn table_sources() {
+        assert!(!allowed("SELECT $1 FROM @stage", Dialect::Databend));
+        assert!(!allowed("SELECT * FROM 's3://bucket/x'", Dialect::Databend));
+    }
+
+    #[test]
+    fn functions() {
+        assert!(!allowed(
+            "SELECT pg_read_file('/etc/passwd')",
+            Dialect::Postgres
+        ));
+        assert!(!allowed(
+            "SELECT set_config('search_path', 'other', false)",
+            Dialect::Postgres
+        ));
+        assert!(!allowed("SELECT pg_catalog.now()", Dialect::Postgres));
+        assert!(!allowed(
+            "SELECT id FROM acme.users WHERE name = current_setting('x')",
+            Dialect::Postgres
+        ));
+        assert!(allowed(
+            "SELECT coalesce(name, 'x'), row_number() OVER (ORDER BY id) FROM acme.users",
+            Dialect::Postgres
+        ));
+    }
+
+    #[test]
+    fn statements() {
+        assert!(!allowed("SET search_path TO other", Dialect::Postgres));
+        assert!(!allowed("DROP DATABASE acme", Dialect::Postgres));
+        assert!(!allowed("CREATE USER x", Dialect::Databend));
+        assert!(!allowed("GRANT ALL ON acme.users TO x", Dialect::Postgres));
+        assert!(!allowed("ATTACH DATABASE 'x.db' AS x", Dialect::Sqlite));
+        assert!(!allowed(
+            "SELECT 1; COPY acme.users TO '/tmp/x'",
+            Dialect::Postgres
+        ));
+        assert!(allowed(
+            "CREATE INDEX users_name_idx ON acme.users (name); DROP TABLE acme.old",
+            Dialect::Postgres
+        ));
+    }
+}
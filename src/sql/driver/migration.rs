@@ -1,128 +1,1017 @@
-use async_graphql::futures_util::StreamExt;
-use databend_driver::Value;
 use riwaq_types::sql::{DDLOp, TableDDL, TableDDLOp};
+use serde::Deserialize;
 
-use super::{
-    databend::DatabendPool,
-    model::{Conn, Pool},
-};
-use std::error::Error;
+use crate::state::org_var;
 
-pub async fn migrate_table<S>(ddl: &TableDDL, pool: DatabendPool, org: S) -> Result<(), Box<dyn Error>> 
-where
-    S: Into<String> + Clone,
-{
-    let t_name = format!("{}.{}", org.into(), ddl.name);
-    let conn = pool.conn().await.unwrap();
-
-    if let TableDDLOp::Drop = ddl.op {
-        let _ = conn.exec(format!("DROP TABLE IF EXISTS {};", t_name)).await;
-        return Ok(());
-    } else if let TableDDLOp::DropAll = ddl.op {
-        let _ = conn
-            .exec(format!("DROP TABLE IF EXISTS {} ALL;", t_name))
-            .await;
-        return Ok(());
-    } else if let TableDDLOp::Undrop = ddl.op {
-        let _ = conn.exec(format!("UNDROP TABLE {};", t_name)).await;
-    }
-
-    let cols = ddl
-        .cols
-        .iter()
-        .map(|col| {
-            format!(
-                "{} {}{}{}",
-                col.name,
-                col.ty,
-                if col.opt { " NULL" } else { "" },
-                if let Some(def) = &col.default {
-                    format!(" DEFAULT {}", riwaq::sql::sql_render_value(def))
-                } else {
-                    "".to_owned()
-                }
-            )
-        })
-        .collect::<Vec<String>>();
+use super::model::{ColumnInfo, Conn, IndexInfo, KeyInfo, KeyKind};
+use std::{collections::HashMap, error::Error, fmt::Display};
 
-    for (i, col) in ddl.cols.iter().enumerate() {
-        if let DDLOp::Rename(old_name) = &col.op {
-            let _ = conn
-                .exec(format!(
-                    "ALTER TABLE IF EXISTS {} RENAME COLUMN {} {};",
-                    t_name, old_name, col.name,
-                ))
-                .await;
+/// Per org table recording the hash of every applied `TableDDL` along with its statements
+pub const HISTORY_TABLE: &str = "_riwaq_migrations";
+
+/// SQL flavour of a backend, each org maps to a Databend database, a PostgreSQL schema
+/// or an attached SQLite database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Databend,
+    Postgres,
+    Sqlite,
+}
+
+impl Dialect {
+    /// `None` when the org's database is created along with the connection
+    pub fn create_database(&self, org: &str) -> Option<String> {
+        match self {
+            Dialect::Databend => Some(format!("CREATE DATABASE IF NOT EXISTS {};", org)),
+            Dialect::Postgres => Some(format!("CREATE SCHEMA IF NOT EXISTS {};", org)),
+            Dialect::Sqlite => None,
+        }
+    }
+
+    /// Whether DDL runs in transactions, migrations are then rolled back as a whole
+    pub fn transactional_ddl(&self) -> bool {
+        match self {
+            Dialect::Databend => false,
+            Dialect::Postgres | Dialect::Sqlite => true,
+        }
+    }
+
+    /// String literal of `v`, databend also treats backslashes as escapes
+    pub fn quote(&self, v: &str) -> String {
+        match self {
+            Dialect::Databend => format!("'{}'", v.replace('\\', "\\\\").replace('\'', "''")),
+            Dialect::Postgres | Dialect::Sqlite => format!("'{}'", v.replace('\'', "''")),
+        }
+    }
+
+    fn drop_table(&self, t_name: &str, all: bool) -> String {
+        match (self, all) {
+            (Dialect::Databend, true) => format!("DROP TABLE IF EXISTS {} ALL;", t_name),
+            (Dialect::Postgres, true) => format!("DROP TABLE IF EXISTS {} CASCADE;", t_name),
+            _ => format!("DROP TABLE IF EXISTS {};", t_name),
+        }
+    }
+
+    /// `None` when the backend keeps no history of dropped tables
+    fn undrop_table(&self, t_name: &str) -> Option<String> {
+        match self {
+            Dialect::Databend => Some(format!("UNDROP TABLE {};", t_name)),
+            Dialect::Postgres | Dialect::Sqlite => None,
+        }
+    }
+
+    fn add_column(&self, t_name: &str, ddl: &TableDDL, i: usize) -> String {
+        let position = match self {
+            Dialect::Databend if i == 0 => " FIRST".to_string(),
+            Dialect::Databend => format!(" AFTER {}", ddl.cols[i - 1].name),
+            Dialect::Postgres | Dialect::Sqlite => "".to_string(),
         };
-        let _ = conn
-            .exec(format!(
-                "ALTER TABLE IF EXISTS {} ADD COLUMN {} {} {}{} {};",
+        format!(
+            "ALTER TABLE {} ADD COLUMN {}{};",
+            t_name,
+            column_def(ddl, i),
+            position
+        )
+    }
+
+    /// `None` when columns can't be altered in place, sqlite tables are rebuilt instead
+    fn modify_column(&self, t_name: &str, ddl: &TableDDL, i: usize) -> Option<String> {
+        let col = &ddl.cols[i];
+        match self {
+            Dialect::Databend => Some(format!(
+                "ALTER TABLE {} MODIFY COLUMN {};",
                 t_name,
-                col.name,
-                col.ty,
-                if col.opt { "NULL" } else { "NOT NULL" },
+                column_def(ddl, i)
+            )),
+            Dialect::Postgres => Some(format!(
+                "ALTER TABLE {t} ALTER COLUMN {c} TYPE {ty} USING {c}::{ty}, ALTER COLUMN {c} {}, ALTER COLUMN {c} {};",
+                if col.opt { "DROP NOT NULL" } else { "SET NOT NULL" },
                 if let Some(def) = &col.default {
-                    format!(" DEFAULT {}", riwaq::sql::sql_render_value(def))
+                    format!("SET DEFAULT {}", riwaq::sql::sql_render_value(def))
                 } else {
-                    "".to_owned()
+                    "DROP DEFAULT".to_owned()
                 },
-                if i == 0 {
-                    "FIRST".to_string()
-                } else {
-                    format!("AFTER {}", ddl.cols.get(i - 1).unwrap().name)
-                }
+                t = t_name,
+                c = col.name,
+                ty = col.ty,
+            )),
+            Dialect::Sqlite => None,
+        }
+    }
+
+    /// Brings column `name` back to the type and nullability it had before a modification,
+    /// its default is not known and stays as modified
+    fn restore_column(&self, t_name: &str, name: &str, current: &ColumnInfo) -> Option<String> {
+        let null = if current.nullable { "NULL" } else { "NOT NULL" };
+        match self {
+            Dialect::Databend => Some(format!(
+                "ALTER TABLE {} MODIFY COLUMN {} {} {};",
+                t_name, name, current.ty, null
+            )),
+            Dialect::Postgres => {
+                Some(format!(
+                "ALTER TABLE {t} ALTER COLUMN {c} TYPE {ty} USING {c}::{ty}, ALTER COLUMN {c} {};",
+                if current.nullable { "DROP NOT NULL" } else { "SET NOT NULL" },
+                t = t_name,
+                c = name,
+                ty = current.ty,
             ))
-            .await;
-        let _ = conn
-            .exec(format!(
-                "ALTER TABLE IF EXISTS {} MODIFY COLUMN {} {}{}{};",
+            }
+            Dialect::Sqlite => None,
+        }
+    }
+
+    /// `None` when the key can't be added to an existing table, sqlite only declares primary
+    /// keys along with the table and databend has no keys besides its cluster key
+    fn add_key(&self, org: &str, table: &str, key: &IndexInfo) -> Option<String> {
+        let cols = key.cols.join(", ");
+        let unique = if key.kind == KeyKind::Unique {
+            "UNIQUE "
+        } else {
+            ""
+        };
+        match (self, key.kind) {
+            (Dialect::Databend, _) | (Dialect::Sqlite, KeyKind::Primary) => None,
+            (Dialect::Postgres, KeyKind::Primary) => Some(format!(
+                "ALTER TABLE {}.{} ADD CONSTRAINT {} PRIMARY KEY ({});",
+                org, table, key.name, cols
+            )),
+            (Dialect::Postgres, KeyKind::Unique) if key.constraint => Some(format!(
+                "ALTER TABLE {}.{} ADD CONSTRAINT {} UNIQUE ({});",
+                org, table, key.name, cols
+            )),
+            (Dialect::Postgres, _) => Some(format!(
+                "CREATE {}INDEX {} ON {}.{} ({});",
+                unique, key.name, org, table, cols
+            )),
+            // sqlite qualifies the index with the schema and the table without it
+            (Dialect::Sqlite, _) => Some(format!(
+                "CREATE {}INDEX {}.{} ON {} ({});",
+                unique, org, key.name, table, cols
+            )),
+        }
+    }
+
+    /// `None` when the key can't be dropped on its own, as sqlite's inline keys
+    fn drop_key(&self, org: &str, table: &str, key: &IndexInfo) -> Option<String> {
+        match self {
+            Dialect::Postgres if key.constraint => Some(format!(
+                "ALTER TABLE {}.{} DROP CONSTRAINT {};",
+                org, table, key.name
+            )),
+            Dialect::Postgres => Some(format!("DROP INDEX {}.{};", org, key.name)),
+            Dialect::Sqlite if !key.constraint => Some(format!("DROP INDEX {}.{};", org, key.name)),
+            Dialect::Sqlite | Dialect::Databend => None,
+        }
+    }
+
+    /// Sets the cluster key, e.g. `(a, b)`, or drops it, `None` when the backend doesn't
+    /// cluster tables
+    fn cluster_by(&self, t_name: &str, key: Option<&str>) -> Option<String> {
+        match (self, key) {
+            (Dialect::Databend, Some(key)) => {
+                Some(format!("ALTER TABLE {} CLUSTER BY {};", t_name, key))
+            }
+            (Dialect::Databend, None) => Some(format!("ALTER TABLE {} DROP CLUSTER KEY;", t_name)),
+            _ => None,
+        }
+    }
+}
+
+/// Keys and indexes a module declares along with its columns, read from the same JSON as its
+/// `TableDDL`, e.g. `"cluster_by": ["day"], "unique": [["org", "email"]]`
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct TableKeys {
+    /// databend only, the other backends don't cluster tables
+    #[serde(default)]
+    pub cluster_by: Vec<String>,
+    #[serde(default)]
+    pub primary_key: Vec<String>,
+    /// column sets unique across the table
+    #[serde(default)]
+    pub unique: Vec<Vec<String>>,
+    /// secondary indexes, databend prunes blocks by itself and has none
+    #[serde(default)]
+    pub indexes: Vec<Vec<String>>,
+}
+
+impl TableKeys {
+    /// Declared keys named after their table and columns, e.g. `{table}_a_b_idx`, postgres
+    /// adds the primary and unique ones as constraints
+    fn declared(&self, table: &str, dialect: Dialect) -> Vec<IndexInfo> {
+        let key = |kind: KeyKind, cols: &Vec<String>| IndexInfo {
+            name: match kind {
+                KeyKind::Primary => format!("{}_pkey", table),
+                KeyKind::Unique => format!("{}_{}_key", table, cols.join("_")),
+                KeyKind::Index => format!("{}_{}_idx", table, cols.join("_")),
+            },
+            kind,
+            cols: cols.clone(),
+            constraint: kind != KeyKind::Index && dialect == Dialect::Postgres,
+        };
+        let primary = (!self.primary_key.is_empty()).then_some(&self.primary_key);
+        primary
+            .map(|cols| key(KeyKind::Primary, cols))
+            .into_iter()
+            .chain(self.unique.iter().map(|cols| key(KeyKind::Unique, cols)))
+            .chain(self.indexes.iter().map(|cols| key(KeyKind::Index, cols)))
+            .collect()
+    }
+
+    fn cluster_key(&self) -> Option<String> {
+        (!self.cluster_by.is_empty()).then(|| format!("({})", self.cluster_by.join(", ")))
+    }
+}
+
+/// Whether `key` is named the way [`TableKeys::declared`] names keys, others were created
+/// outside riwaq (by hand, or expression indexes without plain columns) and are left alone
+fn riwaq_key(table: &str, key: &IndexInfo) -> bool {
+    let name = key.name.to_lowercase();
+    let table = table.to_lowercase();
+    !key.cols.is_empty()
+        && (name == format!("{}_pkey", table)
+            || (name.starts_with(&format!("{}_", table))
+                && (name.ends_with("_key") || name.ends_with("_idx"))))
+}
+
+fn same_key(a: &IndexInfo, b: &IndexInfo) -> bool {
+    a.kind == b.kind
+        && a.cols.len() == b.cols.len()
+        && a.cols
+            .iter()
+            .zip(&b.cols)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+/// Cluster key without its spacing and case, as databend reports it differently
+fn cluster_expr(key: Option<&str>) -> String {
+    key.unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Column definitions of a new table, the primary key is declared along with them except on
+/// databend
+fn table_defs(ddl: &TableDDL, declared: &[IndexInfo], dialect: Dialect) -> Vec<String> {
+    let mut defs = (0..ddl.cols.len())
+        .map(|i| column_def(ddl, i))
+        .collect::<Vec<String>>();
+    if dialect != Dialect::Databend {
+        if let Some(key) = declared.iter().find(|k| k.kind == KeyKind::Primary) {
+            defs.push(format!(
+                "CONSTRAINT {} PRIMARY KEY ({})",
+                key.name,
+                key.cols.join(", ")
+            ));
+        }
+    }
+    defs
+}
+
+/// Steps copying the rows of the sqlite table into a new one created from `ddl` which then
+/// replaces it, the way sqlite changes column types and constraints. `cols` are the columns
+/// the table already has, keys besides the primary one are dropped along with it.
+fn rebuild_table(ddl: &TableDDL, declared: &[IndexInfo], org: &str, cols: &[&str]) -> Vec<Step> {
+    let t_name = format!("{}.{}", org, ddl.name);
+    let tmp = format!("{}_riwaq_rebuild", ddl.name);
+    let mut steps = vec![Step::new(
+        format!(
+            "CREATE TABLE {}.{} ({});",
+            org,
+            tmp,
+            table_defs(ddl, declared, Dialect::Sqlite).join(", ")
+        ),
+        Some(format!("DROP TABLE IF EXISTS {}.{};", org, tmp)),
+    )];
+    if !cols.is_empty() {
+        steps.push(Step::new(
+            format!(
+                "INSERT INTO {}.{} ({c}) SELECT {c} FROM {};",
+                org,
+                tmp,
                 t_name,
-                col.name,
-                col.ty,
-                if col.opt { " NULL" } else { "" },
-                if let Some(def) = &col.default {
-                    format!(" DEFAULT {}", riwaq::sql::sql_render_value(def))
-                } else {
-                    "".to_owned()
-                }
-            ))
-            .await.map_err(|e| dbg!(e));
+                c = cols.join(", ")
+            ),
+            Some(format!("DELETE FROM {}.{};", org, tmp)),
+        ));
     }
+    steps.push(Step {
+        sql: format!("DROP TABLE {};", t_name),
+        destructive: Some(Destructive::RebuildTable),
+        undo: None,
+    });
+    steps.push(Step::new(
+        format!("ALTER TABLE {}.{} RENAME TO {};", org, tmp, ddl.name),
+        Some(format!("ALTER TABLE {} RENAME TO {};", t_name, tmp)),
+    ));
+    steps
+}
 
-    let tbl_exists = conn
-        .conn
-        .query_iter(format!("DESC {}", t_name).as_str())
-        .await;
+fn column_def(ddl: &TableDDL, i: usize) -> String {
+    let col = &ddl.cols[i];
+    format!(
+        "{} {} {}{}",
+        col.name,
+        col.ty,
+        if col.opt { "NULL" } else { "NOT NULL" },
+        if let Some(def) = &col.default {
+            format!(" DEFAULT {}", riwaq::sql::sql_render_value(def))
+        } else {
+            "".to_owned()
+        }
+    )
+}
 
-    if let Ok(mut tbl_des) = tbl_exists {
-        while let Some(row) = tbl_des.next().await {
-            let row = match row {
-                Ok(r) => r,
-                Err(_) => continue,
-            };
-            let col_name = row
-                .values()
-                .get(0)
-                .and_then(|res| match res {
-                    Value::String(v) => Some(v.to_owned()),
-                    _ => None,
-                })
-                .unwrap_or_else(String::new);
-            if let None = ddl.cols.iter().find(|r| r.name == col_name) {
-                let _ = conn
-                    .exec(format!("ALTER TABLE {} DROP COLUMN {};", t_name, col_name))
-                    .await;
+/// A data losing change, refused unless the org's [`DestructivePolicy`] allows it
+#[derive(Debug, Clone)]
+pub enum Destructive {
+    DropTable,
+    DropColumn(String),
+    /// a key or index riwaq created that the module no longer declares
+    DropKey(String),
+    NarrowType {
+        column: String,
+        from: String,
+        to: String,
+    },
+    /// a sqlite table copied into a new one to change its columns, keys and indexes created
+    /// outside riwaq are lost
+    RebuildTable,
+}
+
+impl Display for Destructive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Destructive::DropTable => write!(f, "drop table"),
+            Destructive::DropColumn(column) => write!(f, "drop column {}", column),
+            Destructive::DropKey(key) => write!(f, "drop key {}", key),
+            Destructive::NarrowType { column, from, to } => {
+                write!(f, "narrow column {} from {} to {}", column, from, to)
+            }
+            Destructive::RebuildTable => write!(f, "rebuild table"),
+        }
+    }
+}
+
+/// Destructive changes an org's migrations may run
+#[derive(Debug, Clone, Default)]
+pub struct DestructivePolicy {
+    pub drop_table: bool,
+    pub drop_column: bool,
+    pub drop_key: bool,
+    pub narrow_type: bool,
+    pub rebuild_table: bool,
+}
+
+impl DestructivePolicy {
+    /// Reads `DB_ALLOW_DESTRUCTIVE`, overridable per org (e.g. `DB_ALLOW_DESTRUCTIVE_ACME`):
+    /// `all` or a comma separated list of `drop_table`, `drop_column`, `drop_key`,
+    /// `narrow_type` and `rebuild_table`.
+    /// Nothing destructive is allowed by default.
+    pub fn from_env(org: &str) -> Self {
+        let allowed = org_var("DB_ALLOW_DESTRUCTIVE", org).unwrap_or_default();
+        let allows = |kind: &str| {
+            allowed
+                .split(',')
+                .map(|a| a.trim())
+                .any(|a| a == "all" || a == kind)
+        };
+        Self {
+            drop_table: allows("drop_table"),
+            drop_column: allows("drop_column"),
+            drop_key: allows("drop_key"),
+            narrow_type: allows("narrow_type"),
+            rebuild_table: allows("rebuild_table"),
+        }
+    }
+
+    pub fn allows(&self, change: &Destructive) -> bool {
+        match change {
+            Destructive::DropTable => self.drop_table,
+            Destructive::DropColumn(_) => self.drop_column,
+            Destructive::DropKey(_) => self.drop_key,
+            Destructive::NarrowType { .. } => self.narrow_type,
+            Destructive::RebuildTable => self.rebuild_table,
+        }
+    }
+}
+
+/// A planned statement, flagged when it loses data
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub sql: String,
+    pub destructive: Option<Destructive>,
+    /// compensating statement reverting this one, `None` when it can't be reverted
+    pub undo: Option<String>,
+}
+
+impl Step {
+    fn new(sql: String, undo: Option<String>) -> Self {
+        Self {
+            sql,
+            destructive: None,
+            undo,
+        }
+    }
+}
+
+/// Coarse column types, enough to tell whether a change can lose data
+#[derive(Debug, PartialEq)]
+enum TypeClass {
+    Bool,
+    Int(u8),
+    Float(u8),
+    Decimal(u32, u32),
+    Text(Option<u32>),
+}
+
+/// `None` for types the destructive guard doesn't know, changes to them are never flagged
+fn type_class(ty: &str, dialect: Dialect) -> Option<TypeClass> {
+    let ty = ty.trim().to_lowercase();
+    let ty = ty
+        .strip_prefix("nullable(")
+        .and_then(|t| t.strip_suffix(')'))
+        .unwrap_or(&ty);
+    let ty = ty
+        .trim_end_matches(" not null")
+        .trim_end_matches(" null")
+        .trim();
+    let (base, args) = match ty.split_once('(') {
+        Some((base, args)) => (
+            base.trim(),
+            args.trim_end_matches(')')
+                .split(',')
+                .filter_map(|a| a.trim().parse::<u32>().ok())
+                .collect::<Vec<u32>>(),
+        ),
+        None => (ty, vec![]),
+    };
+    Some(match base {
+        "bool" | "boolean" => TypeClass::Bool,
+        // int8 counts bytes on postgres and bits on databend
+        "int8" if dialect == Dialect::Postgres => TypeClass::Int(64),
+        "tinyint" | "int8" => TypeClass::Int(8),
+        "smallint" | "int16" | "int2" => TypeClass::Int(16),
+        "int" | "integer" | "int32" | "int4" => TypeClass::Int(32),
+        "bigint" | "int64" => TypeClass::Int(64),
+        "float" | "real" | "float32" | "float4" => TypeClass::Float(32),
+        "double" | "double precision" | "float64" | "float8" => TypeClass::Float(64),
+        "decimal" | "numeric" => TypeClass::Decimal(
+            args.first().copied().unwrap_or(38),
+            args.get(1).copied().unwrap_or(0),
+        ),
+        "varchar" | "char" | "character varying" | "character" | "string" | "text" => {
+            TypeClass::Text(args.first().copied())
+        }
+        _ => return None,
+    })
+}
+
+fn narrows(from: &str, to: &str, dialect: Dialect) -> bool {
+    let (from, to) = match (type_class(from, dialect), type_class(to, dialect)) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return false,
+    };
+    match (from, to) {
+        (_, TypeClass::Text(None)) => false,
+        (TypeClass::Text(from), TypeClass::Text(Some(to))) => from.is_none_or(|from| from > to),
+        (TypeClass::Int(from), TypeClass::Int(to))
+        | (TypeClass::Float(from), TypeClass::Float(to)) => to < from,
+        (TypeClass::Decimal(fp, fs), TypeClass::Decimal(tp, ts)) => tp < fp || ts < fs,
+        (TypeClass::Bool, TypeClass::Bool | TypeClass::Int(_)) => false,
+        (TypeClass::Int(_), TypeClass::Float(_) | TypeClass::Decimal(..)) => false,
+        _ => true,
+    }
+}
+
+/// Statements bringing `{org}.{ddl.name}` from its `existing` columns and keys to `ddl` and
+/// `keys`
+pub fn plan_table(
+    ddl: &TableDDL,
+    keys: &TableKeys,
+    org: &str,
+    existing: Option<(&[ColumnInfo], &KeyInfo)>,
+    dialect: Dialect,
+) -> Vec<Step> {
+    let t_name = format!("{}.{}", org, ddl.name);
+
+    let drop_all = match ddl.op {
+        TableDDLOp::Drop => Some(false),
+        TableDDLOp::DropAll => Some(true),
+        _ => None,
+    };
+    if let Some(all) = drop_all {
+        return vec![Step {
+            sql: dialect.drop_table(&t_name, all),
+            destructive: existing.map(|_| Destructive::DropTable),
+            undo: if all {
+                None
+            } else {
+                dialect.undrop_table(&t_name)
+            },
+        }];
+    }
+
+    let declared = keys.declared(&ddl.name, dialect);
+    let add_key = |key: &IndexInfo| {
+        dialect
+            .add_key(org, &ddl.name, key)
+            .map(|sql| Step::new(sql, dialect.drop_key(org, &ddl.name, key)))
+    };
+
+    let (existing, current_keys) = match existing {
+        Some(existing) => existing,
+        None => {
+            let cluster = match dialect {
+                Dialect::Databend => keys.cluster_key().map(|k| format!(" CLUSTER BY {}", k)),
+                Dialect::Postgres | Dialect::Sqlite => None,
             };
+            let mut steps = vec![Step::new(
+                format!(
+                    "CREATE TABLE IF NOT EXISTS {} ({}){};",
+                    t_name,
+                    table_defs(ddl, &declared, dialect).join(", "),
+                    cluster.unwrap_or_default()
+                ),
+                Some(format!("DROP TABLE IF EXISTS {};", t_name)),
+            )];
+            steps.extend(
+                declared
+                    .iter()
+                    .filter(|k| k.kind != KeyKind::Primary)
+                    .filter_map(add_key),
+            );
+            return steps;
+        }
+    };
+    let find = |name: &str| existing.iter().find(|c| c.name == name);
+    let current = |name: &str, op: &DDLOp| match (find(name), op) {
+        (Some(current), _) => Some(current),
+        (None, DDLOp::Rename(old_name)) => find(old_name),
+        _ => None,
+    };
+    // sqlite only adds columns it can fill, nullable or with a default, and alters none in
+    // place, other changes rebuild the table
+    let rebuild = dialect == Dialect::Sqlite
+        && ddl
+            .cols
+            .iter()
+            .any(|col| match current(&col.name, &col.op) {
+                Some(current) => {
+                    !current.ty.eq_ignore_ascii_case(&col.ty) || current.nullable != col.opt
+                }
+                None => !col.opt && col.default.is_none(),
+            });
+
+    let mut steps = vec![];
+    // keys no longer declared go first so their columns can change, new ones come last
+    for key in &current_keys.indexes {
+        if riwaq_key(&ddl.name, key) && !declared.iter().any(|k| same_key(k, key)) {
+            if let Some(sql) = dialect.drop_key(org, &ddl.name, key) {
+                steps.push(Step {
+                    sql,
+                    destructive: Some(Destructive::DropKey(key.name.clone())),
+                    undo: dialect.add_key(org, &ddl.name, key),
+                });
+            }
         }
     }
 
-    let _ = conn
-        .exec(format!(
-            "CREATE TABLE IF NOT EXISTS {} ({});",
-            t_name,
-            cols.join(", ")
+    let mut renamed = vec![];
+    let mut kept = vec![];
+    for (i, col) in ddl.cols.iter().enumerate() {
+        let current = match (find(&col.name), &col.op) {
+            (Some(current), _) => current,
+            (None, DDLOp::Rename(old_name)) if find(old_name).is_some() => {
+                steps.push(Step::new(
+                    format!(
+                        "ALTER TABLE {} RENAME COLUMN {} TO {};",
+                        t_name, old_name, col.name
+                    ),
+                    Some(format!(
+                        "ALTER TABLE {} RENAME COLUMN {} TO {};",
+                        t_name, col.name, old_name
+                    )),
+                ));
+                renamed.push(old_name.as_str());
+                find(old_name).unwrap()
+            }
+            _ if rebuild => continue,
+            _ => {
+                steps.push(Step::new(
+                    dialect.add_column(&t_name, ddl, i),
+                    Some(format!("ALTER TABLE {} DROP COLUMN {};", t_name, col.name)),
+                ));
+                continue;
+            }
+        };
+        kept.push(col.name.as_str());
+        if let Some(sql) = dialect.modify_column(&t_name, ddl, i) {
+            steps.push(Step {
+                sql,
+                destructive: narrows(&current.ty, &col.ty, dialect).then(|| {
+                    Destructive::NarrowType {
+                        column: col.name.clone(),
+                        from: current.ty.clone(),
+                        to: col.ty.clone(),
+                    }
+                }),
+                undo: dialect.restore_column(&t_name, &col.name, current),
+            });
+        }
+    }
+
+    for col in existing {
+        if !ddl.cols.iter().any(|c| c.name == col.name) && !renamed.contains(&col.name.as_str()) {
+            steps.push(Step {
+                sql: format!("ALTER TABLE {} DROP COLUMN {};", t_name, col.name),
+                destructive: Some(Destructive::DropColumn(col.name.clone())),
+                undo: None,
+            });
+        }
+    }
+
+    let cluster = keys.cluster_key();
+    let current_cluster = current_keys.cluster_by.as_deref();
+    if cluster_expr(cluster.as_deref()) != cluster_expr(current_cluster) {
+        if let Some(sql) = dialect.cluster_by(&t_name, cluster.as_deref()) {
+            steps.push(Step::new(sql, dialect.cluster_by(&t_name, current_cluster)));
+        }
+    }
+    if rebuild {
+        steps.extend(rebuild_table(ddl, &declared, org, &kept));
+    }
+    for key in &declared {
+        if rebuild || !current_keys.indexes.iter().any(|k| same_key(k, key)) {
+            steps.extend(add_key(key));
+        }
+    }
+
+    steps
+}
+
+/// Outcome of a table migration, steps in execution order
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub applied: Vec<Step>,
+    /// statement the database rejected with its error, the migration stops at it
+    pub failed: Option<(String, String)>,
+}
+
+impl MigrationReport {
+    /// Runs `step`, returning whether the migration can go on
+    async fn exec<C: Conn>(&mut self, conn: &C, step: Step) -> bool {
+        match conn.exec(step.sql.clone()).await {
+            Ok(_) => self.applied.push(step),
+            Err(e) => self.failed = Some((step.sql, e.to_string())),
+        }
+        self.failed.is_none()
+    }
+}
+
+/// Runs the undo statement of each step in reverse order, describing what happened to each.
+/// Stops at the first step without undo or whose undo fails: the steps before it are kept,
+/// reverting them could drop the only copy of the data.
+pub async fn rollback<C: Conn>(conn: &C, applied: Vec<Step>) -> Vec<String> {
+    let mut res = vec![];
+    let mut steps = applied.into_iter().rev();
+    while let Some(step) = steps.next() {
+        let stopped = match &step.undo {
+            None => format!("not reverted: {}", step.sql),
+            Some(undo) => match conn.exec(undo.clone()).await {
+                Ok(_) => {
+                    res.push(format!("reverted: {} with {}", step.sql, undo));
+                    continue;
+                }
+                Err(e) => format!("revert failed: {} with {} ({})", step.sql, undo, e),
+            },
+        };
+        res.push(stopped);
+        res.extend(steps.map(|s| format!("kept: {}", s.sql)));
+        break;
+    }
+    res
+}
+
+pub async fn migrate_table<C, S>(
+    ddl: &TableDDL,
+    keys: &TableKeys,
+    conn: &C,
+    org: S,
+) -> Result<MigrationReport, Box<dyn Error>>
+where
+    C: Conn,
+    S: Into<String> + Clone,
+{
+    let org: String = org.into();
+    let dialect = conn.dialect();
+    let mut report = MigrationReport::default();
+
+    let t_name = format!("{}.{}", org, ddl.name);
+    if let TableDDLOp::Undrop = ddl.op {
+        if let Some(stmt) = dialect.undrop_table(&t_name) {
+            let undo = Some(dialect.drop_table(&t_name, false));
+            if !report.exec(conn, Step::new(stmt, undo)).await {
+                return Ok(report);
+            }
+        }
+    }
+
+    let existing = conn.columns(org.clone(), ddl.name.clone()).await?;
+    let current_keys = match existing {
+        Some(_) => conn.keys(org.clone(), ddl.name.clone()).await?,
+        None => KeyInfo::default(),
+    };
+    let existing = existing.as_deref().map(|cols| (cols, &current_keys));
+    for step in plan_table(ddl, keys, &org, existing, dialect) {
+        if !report.exec(conn, step).await {
+            break;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Steps `migrate_table` would run against the table as it is now, without running them
+pub async fn plan_migration<C: Conn>(
+    ddl: &TableDDL,
+    keys: &TableKeys,
+    conn: &C,
+    org: &str,
+) -> Result<Vec<Step>, Box<dyn Error>> {
+    let dialect = conn.dialect();
+    let t_name = format!("{}.{}", org, ddl.name);
+    let mut steps = vec![];
+    if let TableDDLOp::Undrop = ddl.op {
+        if let Some(stmt) = dialect.undrop_table(&t_name) {
+            steps.push(Step::new(stmt, Some(dialect.drop_table(&t_name, false))));
+        }
+    }
+    let existing = conn.columns(org.to_string(), ddl.name.clone()).await?;
+    let current_keys = match existing {
+        Some(_) => conn.keys(org.to_string(), ddl.name.clone()).await?,
+        None => KeyInfo::default(),
+    };
+    let existing = existing.as_deref().map(|cols| (cols, &current_keys));
+    steps.extend(plan_table(ddl, keys, org, existing, dialect));
+    Ok(steps)
+}
+
+pub async fn create_history<C: Conn>(conn: &C, org: &str) -> Result<(), Box<dyn Error>> {
+    conn.exec(format!(
+        "CREATE TABLE IF NOT EXISTS {}.{} (table_name VARCHAR NOT NULL, hash VARCHAR NOT NULL, statements VARCHAR NOT NULL, applied_at TIMESTAMP NOT NULL);",
+        org, HISTORY_TABLE
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Hash of the last applied DDL of each table of the org
+pub async fn applied_hashes<C: Conn>(
+    conn: &C,
+    org: &str,
+) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let rows = conn
+        .custom_query(format!(
+            "SELECT table_name, hash FROM {}.{} ORDER BY applied_at;",
+            org, HISTORY_TABLE
         ))
-        .await;
+        .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| match (row.first(), row.get(1)) {
+            (Some(serde_json::Value::String(t)), Some(serde_json::Value::String(h))) => {
+                Some((t.to_owned(), h.to_owned()))
+            }
+            _ => None,
+        })
+        .collect())
+}
 
+pub async fn record_migration<C: Conn>(
+    conn: &C,
+    org: &str,
+    table: &str,
+    hash: &str,
+    stmts: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let dialect = conn.dialect();
+    conn.exec(format!(
+        "INSERT INTO {}.{} (table_name, hash, statements, applied_at) VALUES ({}, {}, {}, {});",
+        org,
+        HISTORY_TABLE,
+        dialect.quote(table),
+        dialect.quote(hash),
+        dialect.quote(&stmts.join("\n")),
+        dialect.quote(
+            &chrono::Utc::now()
+                .format("%Y-%m-%d %H:%M:%S%.6f")
+                .to_string()
+        ),
+    ))
+    .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::sql::driver::{
+        model::{Pool, DB},
+        sqlite::{Sqlite, SqliteConnParams},
+    };
+
+    fn users(cols: serde_json::Value) -> TableDDL {
+        serde_json::from_value(json!({"name": "users", "cols": cols, "op": "Keep"})).unwrap()
+    }
+
+    fn col(name: &str, ty: &str, opt: bool) -> serde_json::Value {
+        json!({"name": name, "ty": ty, "opt": opt, "default": null, "op": "Keep"})
+    }
+
+    fn existing(name: &str, ty: &str, nullable: bool) -> ColumnInfo {
+        ColumnInfo {
+            name: name.to_string(),
+            ty: ty.to_string(),
+            nullable,
+        }
+    }
+
+    fn sql(steps: &[Step]) -> Vec<&str> {
+        steps.iter().map(|s| s.sql.as_str()).collect()
+    }
+
+    #[test]
+    fn plan_diffs() {
+        let keys = TableKeys::default();
+        let ddl = users(json!([
+            col("id", "BIGINT", false),
+            col("name", "TEXT", true)
+        ]));
+        let steps = plan_table(&ddl, &keys, "acme", None, Dialect::Sqlite);
+        assert_eq!(
+            sql(&steps),
+            ["CREATE TABLE IF NOT EXISTS acme.users (id BIGINT NOT NULL, name TEXT NULL);"]
+        );
+        assert_eq!(
+            steps[0].undo.as_deref(),
+            Some("DROP TABLE IF EXISTS acme.users;")
+        );
+
+        let mut renamed = col("full_name", "TEXT", true);
+        renamed["op"] = json!({"Rename": "name"});
+        let ddl = users(json!([
+            col("id", "BIGINT", false),
+            renamed,
+            col("email", "TEXT", true)
+        ]));
+        let current = [
+            existing("id", "BIGINT", false),
+            existing("name", "TEXT", true),
+            existing("age", "INTEGER", true),
+        ];
+        let steps = plan_table(
+            &ddl,
+            &keys,
+            "acme",
+            Some((&current, &KeyInfo::default())),
+            Dialect::Sqlite,
+        );
+        assert_eq!(
+            sql(&steps),
+            [
+                "ALTER TABLE acme.users RENAME COLUMN name TO full_name;",
+                "ALTER TABLE acme.users ADD COLUMN email TEXT NULL;",
+                "ALTER TABLE acme.users DROP COLUMN age;",
+            ]
+        );
+        assert!(steps[..2].iter().all(|s| s.destructive.is_none()));
+        assert!(matches!(
+            &steps[2].destructive,
+            Some(Destructive::DropColumn(c)) if c == "age"
+        ));
+        assert!(steps[2].undo.is_none());
+
+        let dropped: TableDDL =
+            serde_json::from_value(json!({"name": "users", "cols": [], "op": "Drop"})).unwrap();
+        let steps = plan_table(
+            &dropped,
+            &keys,
+            "acme",
+            Some((&current, &KeyInfo::default())),
+            Dialect::Databend,
+        );
+        assert_eq!(sql(&steps), ["DROP TABLE IF EXISTS acme.users;"]);
+        assert!(matches!(steps[0].destructive, Some(Destructive::DropTable)));
+        assert_eq!(steps[0].undo.as_deref(), Some("UNDROP TABLE acme.users;"));
+    }
+
+    #[test]
+    fn narrowing() {
+        assert!(narrows("BIGINT", "INT", Dialect::Postgres));
+        assert!(narrows("VARCHAR(255)", "VARCHAR(10)", Dialect::Postgres));
+        assert!(narrows("TEXT", "VARCHAR(10)", Dialect::Postgres));
+        assert!(narrows(
+            "DECIMAL(10, 2)",
+            "DECIMAL(10, 1)",
+            Dialect::Databend
+        ));
+        assert!(narrows("DOUBLE", "INT", Dialect::Databend));
+        assert!(narrows("Nullable(Int64)", "Int32", Dialect::Databend));
+        // int8 counts bytes on postgres and bits on databend
+        assert!(narrows("INT8", "INT4", Dialect::Postgres));
+        assert!(!narrows("INT8", "INT32", Dialect::Databend));
+
+        assert!(!narrows("INT", "BIGINT", Dialect::Postgres));
+        assert!(!narrows("VARCHAR(10)", "TEXT", Dialect::Postgres));
+        assert!(!narrows("INT", "DOUBLE", Dialect::Databend));
+        assert!(!narrows("BOOLEAN", "INT", Dialect::Postgres));
+        assert!(!narrows("JSONB", "TEXT", Dialect::Postgres));
+    }
+
+    #[test]
+    fn sqlite_rebuild() {
+        let keys = TableKeys {
+            unique: vec![vec!["email".to_string()]],
+            ..Default::default()
+        };
+        let ddl = users(json!([
+            col("id", "BIGINT", false),
+            col("email", "TEXT", false)
+        ]));
+        let current = [
+            existing("id", "BIGINT", false),
+            existing("email", "TEXT", true),
+        ];
+        let steps = plan_table(
+            &ddl,
+            &keys,
+            "acme",
+            Some((&current, &KeyInfo::default())),
+            Dialect::Sqlite,
+        );
+        assert_eq!(
+            sql(&steps)[..4],
+            [
+                "CREATE TABLE acme.users_riwaq_rebuild (id BIGINT NOT NULL, email TEXT NOT NULL);",
+                "INSERT INTO acme.users_riwaq_rebuild (id, email) SELECT id, email FROM acme.users;",
+                "DROP TABLE acme.users;",
+                "ALTER TABLE acme.users_riwaq_rebuild RENAME TO users;",
+            ]
+        );
+        assert!(matches!(
+            steps[2].destructive,
+            Some(Destructive::RebuildTable)
+        ));
+        assert!(steps[2].undo.is_none());
+        assert_eq!(steps.len(), 5);
+        assert!(steps[4].sql.contains("users_email"));
+    }
+
+    #[test]
+    fn rollback_stops_without_undo() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = Sqlite::init(SqliteConnParams::new(
+                "sqlite::memory:".to_string(),
+                "rollback_test".to_string(),
+            ))
+            .unwrap();
+            let conn = pool.conn().await.unwrap();
+            let create = |table: &str| {
+                Step::new(
+                    format!("CREATE TABLE rollback_test.{} (id INTEGER);", table),
+                    Some(format!("DROP TABLE rollback_test.{};", table)),
+                )
+            };
+            let applied = vec![
+                create("kept"),
+                Step {
+                    sql: "DROP TABLE rollback_test.old;".to_string(),
+                    destructive: Some(Destructive::DropTable),
+                    undo: None,
+                },
+                create("added"),
+            ];
+            for step in [&applied[0], &applied[2]] {
+                conn.exec(step.sql.clone()).await.unwrap();
+            }
+
+            assert_eq!(
+                rollback(&conn, applied).await,
+                [
+                    "reverted: CREATE TABLE rollback_test.added (id INTEGER); with DROP TABLE rollback_test.added;",
+                    "not reverted: DROP TABLE rollback_test.old;",
+                    "kept: CREATE TABLE rollback_test.kept (id INTEGER);",
+                ]
+            );
+            let exists = |table: &str| conn.columns("rollback_test".into(), table.into());
+            assert!(exists("kept").await.unwrap().is_some());
+            assert!(exists("added").await.unwrap().is_none());
+        });
+    }
+}
@@ -1,3 +1,10 @@
+pub mod any;
+pub mod bind;
 pub mod databend;
+pub mod error;
+pub mod guard;
 pub mod migration;
 pub mod model;
+pub mod postgres;
+pub mod seed;
+pub mod sqlite;
@@ -1,8 +1,23 @@
-use std::error::Error;
+use std::{
+    error::Error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use async_graphql::futures_util::future::BoxFuture;
+use async_graphql::futures_util::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    StreamExt,
+};
 use serde::Deserialize;
 
+use crate::state::org_var;
+
+use super::{error::SqlError, migration::Dialect};
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct SQLFilter(riwaq::sql::FilterItem);
 impl riwaq::sql::SQLFilterTrait for SQLFilter {
@@ -11,19 +26,125 @@ impl riwaq::sql::SQLFilterTrait for SQLFilter {
     }
 }
 
+/// A column of an existing table, as reported by the database
+#[derive(Debug, Clone)]
+pub struct ColumnInfo {
+    pub name: String,
+    pub ty: String,
+    pub nullable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyKind {
+    Primary,
+    Unique,
+    Index,
+}
+
+/// A key or index of a table, as reported by the database or as declared by a module
+#[derive(Debug, Clone)]
+pub struct IndexInfo {
+    pub name: String,
+    pub kind: KeyKind,
+    pub cols: Vec<String>,
+    /// backs a table constraint, dropped through the table rather than on its own
+    pub constraint: bool,
+}
+
+/// Cluster key and indexes of an existing table
+#[derive(Debug, Clone, Default)]
+pub struct KeyInfo {
+    /// databend cluster key as reported, e.g. `(a, b)`
+    pub cluster_by: Option<String>,
+    pub indexes: Vec<IndexInfo>,
+}
+
+/// Rows of a query as the database sends them, each row's values in column order
+pub type RowStream = BoxStream<'static, Result<Vec<serde_json::Value>, SqlError>>;
+
+/// `rows` ending at their first error, `dirty` is cleared once they are read to their end so
+/// their connection can be reused. It's discarded when they're dropped midway.
+pub fn read_to_end(rows: RowStream, dirty: Arc<AtomicBool>) -> RowStream {
+    stream::unfold(Some((rows, dirty)), |state| async move {
+        let (mut rows, dirty) = state?;
+        match rows.next().await {
+            Some(Ok(row)) => Some((Ok(row), Some((rows, dirty)))),
+            Some(Err(e)) => Some((Err(e), None)),
+            None => {
+                dirty.store(false, Ordering::SeqCst);
+                None
+            }
+        }
+    })
+    .boxed()
+}
+
 pub trait Conn {
-    fn exec<R>(&self, request: R) -> BoxFuture<Result<i64, Box<dyn Error>>>
+    fn exec<R>(&self, request: R) -> BoxFuture<Result<i64, SqlError>>
     where
         R: ToString + Send;
-    fn all(
-        &self,
-        request: riwaq::sql::Select<SQLFilter>,
-    ) -> BoxFuture<Result<Vec<serde_json::Value>, Box<dyn Error>>>;
-
     fn custom_query(
         &self,
         request: String,
-    ) -> BoxFuture<Result<Vec<Vec<serde_json::Value>>, Box<dyn Error>>>;
+    ) -> BoxFuture<Result<Vec<Vec<serde_json::Value>>, SqlError>>;
+
+    /// Rows of the query `request` read from the database as the stream is polled rather than
+    /// buffered. The connection must stay reserved to the stream until it's dropped. Postgres
+    /// reads the rows of a transaction from a declared cursor.
+    fn stream(&self, request: String) -> BoxFuture<Result<RowStream, SqlError>>;
+
+    /// Keeps the connection from going back to its pool once dropped, e.g. when its
+    /// transaction may still be open. Connections that are never reused have nothing to do.
+    fn discard(&self) {}
+
+    /// Columns of `{org}.{table}` in declaration order, `None` when the table does not exist
+    fn columns(
+        &self,
+        org: String,
+        table: String,
+    ) -> BoxFuture<Result<Option<Vec<ColumnInfo>>, SqlError>>;
+
+    /// Cluster key and indexes of `{org}.{table}`, including the ones backing its keys
+    fn keys(&self, org: String, table: String) -> BoxFuture<Result<KeyInfo, SqlError>>;
+
+    /// SQL flavour spoken by the connection, used to render migrations
+    fn dialect(&self) -> Dialect;
+}
+
+/// Bounds of the databend and postgres connection pools
+#[derive(Debug, Clone)]
+pub struct ConnPoolConfig {
+    /// upper bound of connections open at once, callers wait for a free one beyond it
+    pub max: usize,
+    /// idle connections older than this are closed
+    pub idle_timeout: Duration,
+    /// connections are closed once they reach this age, even when busy
+    pub max_lifetime: Duration,
+    /// idle connections are pinged before reuse when idle for longer than this
+    pub check_after: Duration,
+}
+
+impl ConnPoolConfig {
+    /// Reads `DB_POOL_MAX`, `DB_POOL_IDLE_TIMEOUT_MS`, `DB_POOL_MAX_LIFETIME_MS` and
+    /// `DB_POOL_CHECK_AFTER_MS`, overridable per org (e.g. `DB_POOL_MAX_ACME`)
+    pub fn from_env(org: &str) -> Self {
+        let ms = |key: &str, default: u64| {
+            Duration::from_millis(
+                org_var(key, org)
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(default),
+            )
+        };
+        Self {
+            max: org_var("DB_POOL_MAX", org)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(16)
+                .max(1),
+            idle_timeout: ms("DB_POOL_IDLE_TIMEOUT_MS", 600_000),
+            max_lifetime: ms("DB_POOL_MAX_LIFETIME_MS", 1_800_000),
+            check_after: ms("DB_POOL_CHECK_AFTER_MS", 30_000),
+        }
+    }
 }
 
 pub trait ConnParams {}
@@ -31,7 +152,13 @@ pub trait ConnParams {}
 pub trait Pool {
     type ConnType: Conn;
     fn conn(&self) -> BoxFuture<Result<Self::ConnType, Box<dyn Error>>>;
+    /// Connection reserved to one transaction, never shared with other callers while it's open
+    fn tx_conn(&self) -> BoxFuture<Result<Self::ConnType, Box<dyn Error>>> {
+        self.conn()
+    }
     fn disconnect(&self) -> BoxFuture<Result<(), Box<dyn Error>>>;
+    /// Dialect of the pool's connections, known without checking one out
+    fn dialect(&self) -> Dialect;
 }
 
 pub trait DB {
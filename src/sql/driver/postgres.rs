@@ -0,0 +1,502 @@
+use std::{
+    error::Error,
+    fmt::Debug,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use async_graphql::futures_util::{future::BoxFuture, stream, FutureExt, StreamExt};
+use serde::{
+    de::{IgnoredAny, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use serde_json::Value;
+use sqlparser::{
+    ast::{SetExpr, Statement},
+    dialect::PostgreSqlDialect,
+    parser::Parser,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_postgres::{types::ToSql, Client, NoTls, Row, SimpleQueryMessage};
+
+use super::{
+    error::SqlError,
+    migration::Dialect,
+    model::{
+        read_to_end, ColumnInfo, Conn, ConnParams, ConnPoolConfig, IndexInfo, KeyInfo, KeyKind,
+        Pool, RowStream, DB,
+    },
+};
+
+/// Rows fetched at once from a declared cursor
+const FETCH_SIZE: usize = 1000;
+
+static NEXT_CURSOR: AtomicU64 = AtomicU64::new(0);
+
+/// A connection checked out of a [`PostgresPool`], returned to it on drop unless a statement
+/// failed or was dropped midway, or a transaction it began is still open
+pub struct PostgresConn {
+    pub client: Arc<Client>,
+    created: Instant,
+    pool: PostgresPool,
+    _permit: OwnedSemaphorePermit,
+    /// set while a statement runs or its rows are streamed, and kept once one fails
+    dirty: Arc<AtomicBool>,
+    in_tx: AtomicBool,
+}
+
+impl PostgresConn {
+    fn new(
+        client: Arc<Client>,
+        created: Instant,
+        pool: PostgresPool,
+        permit: OwnedSemaphorePermit,
+    ) -> Self {
+        Self {
+            client,
+            created,
+            pool,
+            _permit: permit,
+            dirty: Arc::new(AtomicBool::new(false)),
+            in_tx: AtomicBool::new(false),
+        }
+    }
+
+    async fn tracked<T>(
+        &self,
+        f: impl Future<Output = Result<T, SqlError>>,
+    ) -> Result<T, SqlError> {
+        let dirty = self.dirty.swap(true, Ordering::SeqCst);
+        let res = f.await;
+        if res.is_ok() && !dirty {
+            self.dirty.store(false, Ordering::SeqCst);
+        }
+        res
+    }
+
+    /// Rows of a statement that can't be wrapped (`SHOW`, `EXPLAIN`...), values are their text
+    async fn text_rows(&self, request: &str) -> Result<Vec<Vec<Value>>, SqlError> {
+        Ok(self
+            .client
+            .simple_query(request)
+            .await?
+            .iter()
+            .filter_map(|msg| match msg {
+                SimpleQueryMessage::Row(row) => Some(
+                    (0..row.len())
+                        .map(|i| row.get(i).map_or(Value::Null, Value::from))
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+impl Drop for PostgresConn {
+    fn drop(&mut self) {
+        if self.pool.permits.is_closed()
+            || self.client.is_closed()
+            || self.created.elapsed() >= self.pool.config.max_lifetime
+            || self.dirty.load(Ordering::SeqCst)
+            || self.in_tx.load(Ordering::SeqCst)
+        {
+            return;
+        }
+        self.pool.idle.lock().unwrap().push(IdleConn {
+            client: self.client.clone(),
+            created: self.created,
+            since: Instant::now(),
+        });
+    }
+}
+
+/// The query `request` returning its rows as JSON text, PostgreSQL does the typing through
+/// `row_to_json`
+fn json_select(request: &str) -> String {
+    format!(
+        "SELECT row_to_json(t)::text FROM ({}) t",
+        request.trim().trim_end_matches(';')
+    )
+}
+
+/// `request` returning its rows as JSON text: queries are read as a subquery and data
+/// modifying statements with a `RETURNING` from a `WITH`, `None` for other statements
+fn json_query(request: &str) -> Option<String> {
+    let request = request.trim().trim_end_matches(';');
+    let stmts = Parser::parse_sql(&PostgreSqlDialect {}, request).ok()?;
+    match stmts.as_slice() {
+        [Statement::Query(q)] if !matches!(*q.body, SetExpr::Insert(_) | SetExpr::Update(_)) => {
+            Some(json_select(request))
+        }
+        [Statement::Insert { returning, .. }
+        | Statement::Update { returning, .. }
+        | Statement::Delete { returning, .. }]
+            if returning.is_some() =>
+        {
+            Some(format!(
+                "WITH t AS ({}) SELECT row_to_json(t)::text FROM t",
+                request
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Values of a `row_to_json` object in column order, columns sharing a name included
+struct JsonRow(Vec<Value>);
+
+impl<'de> Deserialize<'de> for JsonRow {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RowVisitor;
+
+        impl<'de> Visitor<'de> for RowVisitor {
+            type Value = JsonRow;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a JSON object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<JsonRow, A::Error> {
+                let mut values = vec![];
+                while let Some((_, value)) = map.next_entry::<IgnoredAny, Value>()? {
+                    values.push(value);
+                }
+                Ok(JsonRow(values))
+            }
+        }
+
+        deserializer.deserialize_map(RowVisitor)
+    }
+}
+
+fn json_row(row: &Row) -> Result<Vec<Value>, SqlError> {
+    Ok(serde_json::from_str::<JsonRow>(&row.try_get::<_, String>(0)?)?.0)
+}
+
+impl Conn for PostgresConn {
+    fn exec<R>(&self, request: R) -> BoxFuture<Result<i64, SqlError>>
+    where
+        R: ToString + Send,
+    {
+        let req = request.to_string();
+        self.tracked(async move {
+            let res = self.client.execute(&req, &[]).await? as i64;
+            match req.trim() {
+                "BEGIN" => self.in_tx.store(true, Ordering::SeqCst),
+                "COMMIT" | "ROLLBACK" => self.in_tx.store(false, Ordering::SeqCst),
+                _ => {}
+            }
+            Ok(res)
+        })
+        .boxed()
+    }
+
+    fn custom_query(
+        &self,
+        request: String,
+    ) -> BoxFuture<Result<Vec<Vec<serde_json::Value>>, SqlError>> {
+        self.tracked(async move {
+            let query = match json_query(&request) {
+                Some(query) => query,
+                None => return self.text_rows(&request).await,
+            };
+            self.client
+                .query(&query, &[])
+                .await?
+                .iter()
+                .map(json_row)
+                .collect()
+        })
+        .boxed()
+    }
+
+    /// Declares a cursor over `request` in the open transaction, rows are fetched
+    /// [`FETCH_SIZE`] at a time as the stream is read. The connection isn't reused afterwards,
+    /// the stream may outlive the transaction. Outside of a transaction rows are read as the
+    /// server sends them, and the connection is reused once they were all read.
+    fn stream(&self, request: String) -> BoxFuture<Result<RowStream, SqlError>> {
+        async move {
+            self.dirty.store(true, Ordering::SeqCst);
+            if !self.in_tx.load(Ordering::SeqCst) {
+                let rows = self
+                    .client
+                    .query_raw(
+                        &json_select(&request),
+                        std::iter::empty::<&(dyn ToSql + Sync)>(),
+                    )
+                    .await?
+                    .map(|row| json_row(&row?))
+                    .boxed();
+                return Ok(read_to_end(rows, self.dirty.clone()));
+            }
+            let name = format!(
+                "riwaq_cursor_{}",
+                NEXT_CURSOR.fetch_add(1, Ordering::Relaxed)
+            );
+            self.client
+                .batch_execute(&format!(
+                    "DECLARE {} NO SCROLL CURSOR FOR {}",
+                    name,
+                    json_select(&request)
+                ))
+                .await?;
+            let fetch = format!("FETCH {} FROM {}", FETCH_SIZE, name);
+            let batches = stream::unfold(Some(self.client.clone()), move |client| {
+                let fetch = fetch.clone();
+                async move {
+                    let client = client?;
+                    match client.query(&fetch, &[]).await {
+                        Ok(rows) if rows.is_empty() => None,
+                        Ok(rows) => {
+                            let batch = rows.iter().map(json_row).collect::<Vec<_>>();
+                            Some((batch, Some(client)))
+                        }
+                        // the cursor is gone with its transaction, nothing more to fetch
+                        Err(e) => Some((vec![Err(e.into())], None)),
+                    }
+                }
+            });
+            Ok(batches.flat_map(stream::iter).boxed())
+        }
+        .boxed()
+    }
+
+    fn discard(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    fn columns(
+        &self,
+        org: String,
+        table: String,
+    ) -> BoxFuture<Result<Option<Vec<ColumnInfo>>, SqlError>> {
+        self.tracked(async move {
+            let rows = self
+                .client
+                .query(
+                    // format_type keeps lengths and precisions, e.g. `character varying(50)`
+                    "SELECT a.attname::text, format_type(a.atttypid, a.atttypmod), NOT a.attnotnull \
+                    FROM pg_attribute a JOIN pg_class c ON a.attrelid = c.oid JOIN pg_namespace n ON c.relnamespace = n.oid \
+                    WHERE n.nspname = $1 AND c.relname = $2 AND a.attnum > 0 AND NOT a.attisdropped ORDER BY a.attnum",
+                    &[&org, &table],
+                )
+                .await?;
+            let res = rows
+                .into_iter()
+                .map(|row| -> Result<ColumnInfo, SqlError> {
+                    Ok(ColumnInfo {
+                        name: row.try_get(0)?,
+                        ty: row.try_get(1)?,
+                        nullable: row.try_get(2)?,
+                    })
+                })
+                .collect::<Result<Vec<ColumnInfo>, SqlError>>()?;
+            Ok(if res.is_empty() { None } else { Some(res) })
+        })
+        .boxed()
+    }
+
+    fn keys(&self, org: String, table: String) -> BoxFuture<Result<KeyInfo, SqlError>> {
+        self.tracked(async move {
+            let rows = self
+                .client
+                .query(
+                    // index columns in key order, expression indexes only list plain columns
+                    "SELECT ic.relname::text, i.indisprimary, i.indisunique, con.oid IS NOT NULL, \
+                    array_to_string(ARRAY(SELECT a.attname FROM unnest(i.indkey::int2[]) WITH ORDINALITY k(num, ord) \
+                    JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = k.num ORDER BY k.ord), ',') \
+                    FROM pg_index i JOIN pg_class ic ON ic.oid = i.indexrelid JOIN pg_class c ON c.oid = i.indrelid \
+                    JOIN pg_namespace n ON n.oid = c.relnamespace \
+                    LEFT JOIN pg_constraint con ON con.conindid = i.indexrelid AND con.contype IN ('p', 'u') \
+                    WHERE n.nspname = $1 AND c.relname = $2",
+                    &[&org, &table],
+                )
+                .await?;
+            let indexes = rows
+                .into_iter()
+                .map(|row| -> Result<IndexInfo, SqlError> {
+                    Ok(IndexInfo {
+                        name: row.try_get(0)?,
+                        kind: match (row.try_get::<_, bool>(1)?, row.try_get::<_, bool>(2)?) {
+                            (true, _) => KeyKind::Primary,
+                            (_, true) => KeyKind::Unique,
+                            _ => KeyKind::Index,
+                        },
+                        constraint: row.try_get(3)?,
+                        cols: row
+                            .try_get::<_, String>(4)?
+                            .split(',')
+                            .filter(|c| !c.is_empty())
+                            .map(|c| c.to_string())
+                            .collect(),
+                    })
+                })
+                .collect::<Result<Vec<IndexInfo>, SqlError>>()?;
+            Ok(KeyInfo {
+                cluster_by: None,
+                indexes,
+            })
+        })
+        .boxed()
+    }
+
+    fn dialect(&self) -> Dialect {
+        Dialect::Postgres
+    }
+}
+
+pub struct PostgresConnParams {
+    conn_str: String,
+    config: ConnPoolConfig,
+}
+impl PostgresConnParams {
+    pub fn new(conn_str: String, config: ConnPoolConfig) -> Self {
+        Self { conn_str, config }
+    }
+}
+
+impl ConnParams for PostgresConnParams {}
+
+struct IdleConn {
+    client: Arc<Client>,
+    created: Instant,
+    since: Instant,
+}
+
+/// Bounded pool of connections reused across handler calls
+#[derive(Clone)]
+pub struct PostgresPool {
+    pub conn_str: String,
+    config: ConnPoolConfig,
+    idle: Arc<Mutex<Vec<IdleConn>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl Debug for PostgresPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PostgresPool")
+            .field("conn_str", &self.conn_str)
+            .field("config", &self.config)
+            .field("idle", &self.idle.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Pool for PostgresPool {
+    type ConnType = PostgresConn;
+
+    fn conn(&self) -> BoxFuture<Result<PostgresConn, Box<dyn Error>>> {
+        async {
+            let permit = self
+                .permits
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(|_| "database pool is disconnected")?;
+
+            let config = &self.config;
+            self.idle.lock().unwrap().retain(|c| {
+                !c.client.is_closed()
+                    && c.since.elapsed() < config.idle_timeout
+                    && c.created.elapsed() < config.max_lifetime
+            });
+
+            // most recently used first, so surplus connections idle out
+            loop {
+                let idle = self.idle.lock().unwrap().pop();
+                let idle = match idle {
+                    Some(idle) => idle,
+                    None => break,
+                };
+                if idle.since.elapsed() >= config.check_after
+                    && idle.client.simple_query("SELECT 1").await.is_err()
+                {
+                    continue;
+                }
+                return Ok(PostgresConn::new(
+                    idle.client,
+                    idle.created,
+                    self.clone(),
+                    permit,
+                ));
+            }
+
+            let (client, connection) = tokio_postgres::connect(&self.conn_str, NoTls).await?;
+            // the connection drives the socket and ends once the client is dropped
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    tracing::warn!("postgres connection closed: {}", e);
+                }
+            });
+            Ok(PostgresConn::new(
+                Arc::new(client),
+                Instant::now(),
+                self.clone(),
+                permit,
+            ))
+        }
+        .boxed()
+    }
+
+    /// Closes idle connections and refuses new checkouts, busy ones close once released
+    fn disconnect(&self) -> BoxFuture<Result<(), Box<dyn Error>>> {
+        async {
+            self.permits.close();
+            self.idle.lock().unwrap().clear();
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn dialect(&self) -> Dialect {
+        Dialect::Postgres
+    }
+}
+
+pub struct Postgres {}
+
+impl DB for Postgres {
+    type ParamsType = PostgresConnParams;
+    type PoolType = PostgresPool;
+
+    fn init(params: PostgresConnParams) -> Result<PostgresPool, Box<dyn Error>> {
+        Ok(PostgresPool {
+            conn_str: params.conn_str,
+            permits: Arc::new(Semaphore::new(params.config.max)),
+            idle: Arc::new(Mutex::new(vec![])),
+            config: params.config,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn json_rows() {
+        let row = serde_json::from_str::<JsonRow>(r#"{"b": 1, "a": [2], "b": null}"#).unwrap();
+        assert_eq!(row.0, vec![json!(1), json!([2]), Value::Null]);
+    }
+
+    #[test]
+    fn json_queries() {
+        assert_eq!(
+            json_query("SELECT a FROM acme.t ORDER BY a;").unwrap(),
+            "SELECT row_to_json(t)::text FROM (SELECT a FROM acme.t ORDER BY a) t"
+        );
+        assert_eq!(
+            json_query("INSERT INTO acme.t (a) VALUES (1) RETURNING a").unwrap(),
+            "WITH t AS (INSERT INTO acme.t (a) VALUES (1) RETURNING a) SELECT row_to_json(t)::text FROM t"
+        );
+        assert!(json_query("INSERT INTO acme.t (a) VALUES (1)").is_none());
+        assert!(json_query("SHOW search_path").is_none());
+    }
+}
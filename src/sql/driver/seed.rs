@@ -0,0 +1,144 @@
+use std::error::Error;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::{bind::literal, migration::Dialect, model::Conn};
+
+/// Reference rows a module ships along with its tables, exported as `riwaq_seed_*`, e.g.
+/// `{"table": "countries", "key": ["code"], "rows": [{"code": "TN", "name": "Tunisia"}]}`
+#[derive(Deserialize, Debug, Clone)]
+pub struct Seed {
+    pub table: String,
+    /// columns identifying a row, all of the row's columns when empty
+    #[serde(default)]
+    pub key: Vec<String>,
+    pub rows: Vec<Map<String, Value>>,
+}
+
+impl Seed {
+    /// Statements bringing the table's rows to the seed's: rows sharing a key with a seed row
+    /// are updated and missing ones inserted, applying them again changes nothing
+    pub fn statements(&self, org: &str, dialect: Dialect) -> Result<Vec<String>, Box<dyn Error>> {
+        check_identifier(&self.table)?;
+        let t_name = format!("{}.{}", org, self.table);
+        let mut stmts = vec![];
+        for row in &self.rows {
+            row.keys().try_for_each(|col| check_identifier(col))?;
+            if row.is_empty() || !self.key.iter().all(|k| row.contains_key(k)) {
+                return Err(format!(
+                    "seed of {}: rows must set their key columns ({})",
+                    self.table,
+                    self.key.join(", ")
+                )
+                .into());
+            }
+            let is_key = |col: &str| self.key.is_empty() || self.key.iter().any(|k| k == col);
+            let cond = row
+                .iter()
+                .filter(|(col, _)| is_key(col))
+                .map(|(col, value)| match value {
+                    Value::Null => format!("{} IS NULL", col),
+                    value => format!("{} = {}", col, literal(value, dialect)),
+                })
+                .collect::<Vec<String>>()
+                .join(" AND ");
+            let set = row
+                .iter()
+                .filter(|(col, _)| !is_key(col))
+                .map(|(col, value)| format!("{} = {}", col, literal(value, dialect)))
+                .collect::<Vec<String>>();
+            if !set.is_empty() {
+                stmts.push(format!(
+                    "UPDATE {} SET {} WHERE {};",
+                    t_name,
+                    set.join(", "),
+                    cond
+                ));
+            }
+            stmts.push(format!(
+                "INSERT INTO {t} ({}) SELECT {} WHERE NOT EXISTS (SELECT 1 FROM {t} WHERE {});",
+                row.keys().cloned().collect::<Vec<String>>().join(", "),
+                row.values()
+                    .map(|value| literal(value, dialect))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                cond,
+                t = t_name,
+            ));
+        }
+        Ok(stmts)
+    }
+}
+
+/// Seeds are written as SQL without going through the tenant guard, so the table and column
+/// names they interpolate must be plain identifiers
+fn check_identifier(name: &str) -> Result<(), Box<dyn Error>> {
+    let mut chars = name.chars();
+    let plain = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !plain {
+        return Err(format!("seed: '{}' is not a valid table or column name", name).into());
+    }
+    Ok(())
+}
+
+/// Applies `seed` to the org's table, returning the number of rows it holds
+pub async fn apply_seed<C: Conn>(
+    seed: &Seed,
+    conn: &C,
+    org: &str,
+) -> Result<usize, Box<dyn Error>> {
+    let statements = seed.statements(org, conn.dialect())?;
+    for stmt in statements {
+        conn.exec(stmt).await?;
+    }
+    Ok(seed.rows.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn seed(value: Value) -> Seed {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn upserts() {
+        let countries = seed(json!({
+            "table": "countries",
+            "key": ["code"],
+            "rows": [{"code": "TN", "name": "Tunisia"}, {"code": "FR", "name": "L'Hexagone"}]
+        }));
+        assert_eq!(
+            countries.statements("acme", Dialect::Postgres).unwrap(),
+            [
+                "UPDATE acme.countries SET name = 'Tunisia' WHERE code = 'TN';",
+                "INSERT INTO acme.countries (code, name) SELECT 'TN', 'Tunisia' WHERE NOT EXISTS (SELECT 1 FROM acme.countries WHERE code = 'TN');",
+                "UPDATE acme.countries SET name = 'L''Hexagone' WHERE code = 'FR';",
+                "INSERT INTO acme.countries (code, name) SELECT 'FR', 'L''Hexagone' WHERE NOT EXISTS (SELECT 1 FROM acme.countries WHERE code = 'FR');",
+            ]
+        );
+
+        let tags = seed(json!({"table": "tags", "rows": [{"name": "a", "parent": null}]}));
+        assert_eq!(
+            tags.statements("acme", Dialect::Sqlite).unwrap(),
+            ["INSERT INTO acme.tags (name, parent) SELECT 'a', NULL WHERE NOT EXISTS (SELECT 1 FROM acme.tags WHERE name = 'a' AND parent IS NULL);"]
+        );
+    }
+
+    #[test]
+    fn invalid_seeds() {
+        let missing_key = seed(json!({"table": "t", "key": ["id"], "rows": [{"name": "a"}]}));
+        assert!(missing_key.statements("acme", Dialect::Postgres).is_err());
+        let table = seed(json!({"table": "t; DROP TABLE x", "rows": [{"id": 1}]}));
+        assert!(table.statements("acme", Dialect::Postgres).is_err());
+        let column = seed(json!({"table": "t", "rows": [{"id = 1 OR 1": 1}]}));
+        assert!(column.statements("acme", Dialect::Postgres).is_err());
+    }
+}
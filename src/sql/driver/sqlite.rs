@@ -0,0 +1,285 @@
+use std::{
+    error::Error,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_graphql::futures_util::{future::BoxFuture, stream, FutureExt, StreamExt};
+use rusqlite::{types::ValueRef, Connection};
+use tokio::sync::mpsc;
+
+use super::{
+    error::SqlError,
+    migration::Dialect,
+    model::{ColumnInfo, Conn, ConnParams, IndexInfo, KeyInfo, KeyKind, Pool, RowStream, DB},
+};
+
+/// Statements run inline on the shared connection, sqlite calls are local and short
+pub struct SqliteConn {
+    pub conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteConn {
+    fn query_rows(
+        &self,
+        request: &str,
+    ) -> Result<(Vec<String>, Vec<Vec<serde_json::Value>>), SqlError> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn.prepare(request)?;
+        let cols = stmt
+            .column_names()
+            .into_iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<String>>();
+        let mut rows = stmt.query([])?;
+        let mut res = vec![];
+        while let Some(row) = rows.next()? {
+            res.push(
+                (0..cols.len())
+                    .map(|i| row.get_ref(i).map(sqlite_to_serde))
+                    .collect::<Result<Vec<serde_json::Value>, rusqlite::Error>>()?,
+            );
+        }
+        Ok((cols, res))
+    }
+}
+
+impl Conn for SqliteConn {
+    fn exec<R>(&self, request: R) -> BoxFuture<Result<i64, SqlError>>
+    where
+        R: ToString + Send,
+    {
+        let req = request.to_string();
+        async move {
+            let conn = self.conn.lock().map_err(|e| e.to_string())?;
+            Ok(conn.execute(&req, [])? as i64)
+        }
+        .boxed()
+    }
+
+    fn custom_query(
+        &self,
+        request: String,
+    ) -> BoxFuture<Result<Vec<Vec<serde_json::Value>>, SqlError>> {
+        async move { Ok(self.query_rows(&request)?.1) }.boxed()
+    }
+
+    /// Rows are stepped on a thread of their own holding the connection until the stream is
+    /// read to its end or dropped, other statements on it wait meanwhile
+    fn stream(&self, request: String) -> BoxFuture<Result<RowStream, SqlError>> {
+        async move {
+            let conn = self.conn.clone();
+            let (tx, rx) = mpsc::channel::<Result<Vec<serde_json::Value>, SqlError>>(64);
+            std::thread::spawn(move || {
+                let res = (|| -> Result<(), SqlError> {
+                    let conn = conn.lock().map_err(|e| e.to_string())?;
+                    let mut stmt = conn.prepare(&request)?;
+                    let cols = stmt.column_count();
+                    let mut rows = stmt.query([])?;
+                    while let Some(row) = rows.next()? {
+                        let row = (0..cols)
+                            .map(|i| row.get_ref(i).map(sqlite_to_serde))
+                            .collect::<Result<Vec<serde_json::Value>, rusqlite::Error>>()?;
+                        if tx.blocking_send(Ok(row)).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(())
+                })();
+                if let Err(e) = res {
+                    let _ = tx.blocking_send(Err(e));
+                }
+            });
+            Ok(stream::unfold(
+                rx,
+                |mut rx| async move { rx.recv().await.map(|row| (row, rx)) },
+            )
+            .boxed())
+        }
+        .boxed()
+    }
+
+    fn columns(
+        &self,
+        org: String,
+        table: String,
+    ) -> BoxFuture<Result<Option<Vec<ColumnInfo>>, SqlError>> {
+        async move {
+            let conn = self.conn.lock().map_err(|e| e.to_string())?;
+            let mut stmt = conn.prepare(&format!(
+                "PRAGMA {}.table_info({});",
+                ident(&org),
+                ident(&table)
+            ))?;
+            let res = stmt
+                .query_map([], |row| {
+                    Ok(ColumnInfo {
+                        name: row.get("name")?,
+                        ty: row.get("type")?,
+                        nullable: row.get::<_, i64>("notnull")? == 0,
+                    })
+                })?
+                .collect::<Result<Vec<ColumnInfo>, rusqlite::Error>>()?;
+            Ok(if res.is_empty() { None } else { Some(res) })
+        }
+        .boxed()
+    }
+
+    /// Indexes backing inline keys (`origin` other than `c`) can't be dropped on their own
+    fn keys(&self, org: String, table: String) -> BoxFuture<Result<KeyInfo, SqlError>> {
+        async move {
+            let conn = self.conn.lock().map_err(|e| e.to_string())?;
+            let mut stmt = conn.prepare(&format!(
+                "PRAGMA {}.index_list({});",
+                ident(&org),
+                ident(&table)
+            ))?;
+            let list = stmt
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>("name")?,
+                        row.get::<_, i64>("unique")? == 1,
+                        row.get::<_, String>("origin")?,
+                    ))
+                })?
+                .collect::<Result<Vec<(String, bool, String)>, rusqlite::Error>>()?;
+            let mut indexes = vec![];
+            for (name, unique, origin) in list {
+                let mut stmt = conn.prepare(&format!(
+                    "PRAGMA {}.index_info({});",
+                    ident(&org),
+                    ident(&name)
+                ))?;
+                let cols = stmt
+                    .query_map([], |row| row.get::<_, String>("name"))?
+                    .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+                indexes.push(IndexInfo {
+                    name,
+                    kind: match (origin.as_str(), unique) {
+                        ("pk", _) => KeyKind::Primary,
+                        (_, true) => KeyKind::Unique,
+                        _ => KeyKind::Index,
+                    },
+                    cols,
+                    constraint: origin != "c",
+                });
+            }
+            Ok(KeyInfo {
+                cluster_by: None,
+                indexes,
+            })
+        }
+        .boxed()
+    }
+
+    fn dialect(&self) -> Dialect {
+        Dialect::Sqlite
+    }
+}
+
+/// `name` quoted as an identifier, org and table names come from the loaded modules
+fn ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+pub struct SqliteConnParams {
+    url: String,
+    org: String,
+}
+impl SqliteConnParams {
+    pub fn new(url: String, org: String) -> Self {
+        Self { url, org }
+    }
+}
+
+impl ConnParams for SqliteConnParams {}
+
+/// A single connection shared by the org's modules, the org's database is attached to it under
+/// the org name so tables are addressed as `{org}.{table}` like on the other backends
+#[derive(Clone, Debug)]
+pub struct SqlitePool {
+    pub conn: Arc<Mutex<Connection>>,
+    path: String,
+    org: String,
+}
+
+impl SqlitePool {
+    /// New connection with the org's database attached, waits for the locks of other
+    /// connections instead of failing right away
+    fn open(path: &str, org: &str) -> Result<Connection, Box<dyn Error>> {
+        let conn = Connection::open_in_memory()?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute(&format!("ATTACH DATABASE ?1 AS {};", ident(org)), [path])?;
+        Ok(conn)
+    }
+}
+
+impl Pool for SqlitePool {
+    type ConnType = SqliteConn;
+
+    fn conn(&self) -> BoxFuture<Result<SqliteConn, Box<dyn Error>>> {
+        async {
+            Ok(SqliteConn {
+                conn: self.conn.clone(),
+            })
+        }
+        .boxed()
+    }
+
+    /// A connection of its own, statements of other handlers would otherwise end up in the
+    /// transaction
+    fn tx_conn(&self) -> BoxFuture<Result<SqliteConn, Box<dyn Error>>> {
+        async {
+            Ok(SqliteConn {
+                conn: Arc::new(Mutex::new(SqlitePool::open(&self.path, &self.org)?)),
+            })
+        }
+        .boxed()
+    }
+
+    fn disconnect(&self) -> BoxFuture<Result<(), Box<dyn Error>>> {
+        async { Ok(()) }.boxed()
+    }
+
+    fn dialect(&self) -> Dialect {
+        Dialect::Sqlite
+    }
+}
+
+pub struct Sqlite {}
+
+impl DB for Sqlite {
+    type ParamsType = SqliteConnParams;
+    type PoolType = SqlitePool;
+
+    /// `sqlite::memory:` keeps the org in memory for as long as one of its modules is loaded,
+    /// `sqlite://{path}` stores it in a file, e.g. `sqlite://./data/{{org}}.db`
+    fn init(params: SqliteConnParams) -> Result<SqlitePool, Box<dyn Error>> {
+        let path = match params.url.as_str() {
+            "sqlite::memory:" | "sqlite://:memory:" => {
+                format!("file:riwaq_{}?mode=memory&cache=shared", params.org)
+            }
+            url => url.trim_start_matches("sqlite://").to_string(),
+        };
+        Ok(SqlitePool {
+            conn: Arc::new(Mutex::new(SqlitePool::open(&path, &params.org)?)),
+            path,
+            org: params.org,
+        })
+    }
+}
+
+fn sqlite_to_serde(value: ValueRef) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(n) => serde_json::Value::from(n),
+        ValueRef::Real(n) => serde_json::Value::from(n),
+        ValueRef::Text(v) => serde_json::Value::from(String::from_utf8_lossy(v).to_string()),
+        ValueRef::Blob(v) => serde_json::Value::from(
+            v.iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<String>>()
+                .concat(),
+        ),
+    }
+}
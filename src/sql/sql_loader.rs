@@ -1,45 +1,204 @@
 use riwaq_types::sql::TableDDL;
 use serde::Deserialize;
-use std::error::Error;
-use wasmer::{Extern, Instance, MemoryView};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, error::Error};
+use wasmer::{Extern, Function, Instance, Memory, MemoryView};
 
 use crate::sql::driver::{
-    databend::{Databend, DatabendConnParams},
+    any::{AnyConnParams, AnyDB},
     model::DB,
 };
 
 use super::driver::{
-    databend::DatabendPool,
-    migration::migrate_table,
+    any::{AnyConn, AnyPool},
+    migration::{
+        applied_hashes, create_history, migrate_table, plan_migration, record_migration, rollback,
+        DestructivePolicy, Dialect, Step, TableKeys,
+    },
     model::{Conn, Pool},
+    seed::{apply_seed, Seed},
 };
 
+#[derive(Debug)]
+pub struct SqlTable {
+    pub ddl: TableDDL,
+    pub keys: TableKeys,
+    /// sha256 of the DDL as exported by the module, recorded once the table is migrated
+    pub hash: String,
+}
+
+/// Seed exported as `riwaq_seed_{name}`
+#[derive(Debug)]
+pub struct SqlSeed {
+    pub name: String,
+    pub seed: Seed,
+    /// sha256 of the seed as exported by the module, recorded once it's applied
+    pub hash: String,
+}
+
+impl SqlSeed {
+    /// Name the seed is recorded under in the migration history, apart from the tables
+    fn history_name(&self) -> String {
+        format!("seed:{}", self.name)
+    }
+
+    async fn apply<C: Conn>(&self, conn: &C, org: &str) -> Result<usize, Box<dyn Error>> {
+        let rows = apply_seed(&self.seed, conn, org).await.map_err(|e| {
+            format!(
+                "{}.{}: seed {} failed: {}",
+                org, self.seed.table, self.name, e
+            )
+        })?;
+        tracing::info!(org = %org, table = %self.seed.table, "seed {}: {} rows", self.name, rows);
+        Ok(rows)
+    }
+}
+
+/// Statements a migration would run on a table, empty when the table is up to date
+#[derive(Debug, async_graphql::SimpleObject)]
+pub struct TablePlan {
+    pub table: String,
+    pub statements: Vec<String>,
+    /// data losing changes among the statements
+    pub destructive: Vec<String>,
+}
+
 #[derive(Debug)]
 pub struct SqlModule {
-    tables: Vec<TableDDL>,
-    pub pool: Option<DatabendPool>,
+    tables: Vec<SqlTable>,
+    seeds: Vec<SqlSeed>,
+    /// url `pool` was opened for
+    pub db_url: Option<String>,
+    pub pool: Option<AnyPool>,
 }
 
 impl SqlModule {
-    pub async fn migrate<S>(&self, org: S) -> Result<(), Box<dyn Error>>
-    where
-        S: Into<String> + Clone,
-    {
-        let pool = self.pool.clone().ok_or("migration: no database pool")?;
+    /// Migrates the tables whose DDL changed since their last migration on `conn`, stopping at
+    /// the first failing statement. Steps that ran are appended to `applied` so they can be
+    /// reverted. The migrated tables are returned with their hash and statements to be recorded.
+    async fn migrate(
+        &self,
+        conn: &AnyConn,
+        org: &str,
+        applied: &mut Vec<Step>,
+    ) -> Result<Vec<(String, String, Vec<String>)>, Box<dyn Error>> {
+        if let Some(stmt) = conn.dialect().create_database(org) {
+            conn.exec(stmt).await?;
+        }
+        create_history(conn, org).await?;
+        let hashes = applied_hashes(conn, org).await?;
+
+        let mut migrated = vec![];
+        for t in &self.tables {
+            if hashes.get(&t.ddl.name) == Some(&t.hash) {
+                continue;
+            }
+
+            let report = migrate_table(&t.ddl, &t.keys, conn, org).await?;
+            let stmts = report
+                .applied
+                .iter()
+                .map(|s| s.sql.clone())
+                .collect::<Vec<String>>();
+            for stmt in &stmts {
+                tracing::info!(org = %org, table = %t.ddl.name, "migration: {}", stmt);
+            }
+            applied.extend(report.applied);
+            if let Some((stmt, e)) = report.failed {
+                return Err(format!("{}.{}: {} failed: {}", org, t.ddl.name, stmt, e).into());
+            }
+            migrated.push((t.ddl.name.clone(), t.hash.clone(), stmts));
+        }
+        Ok(migrated)
+    }
+
+    /// Applies the seeds on `conn` that changed or whose table is among the `migrated` ones,
+    /// returning them with their hash and statements to be recorded
+    async fn apply_seeds(
+        &self,
+        conn: &AnyConn,
+        org: &str,
+        migrated: &[(String, String, Vec<String>)],
+    ) -> Result<Vec<(String, String, Vec<String>)>, Box<dyn Error>> {
+        if self.seeds.is_empty() {
+            return Ok(vec![]);
+        }
+        let hashes = applied_hashes(conn, org).await?;
+
+        let mut seeded = vec![];
+        for s in &self.seeds {
+            let table_migrated = migrated.iter().any(|(name, ..)| *name == s.seed.table);
+            if !table_migrated && hashes.get(&s.history_name()) == Some(&s.hash) {
+                continue;
+            }
+            let rows = s.apply(conn, org).await?;
+            seeded.push((
+                s.history_name(),
+                s.hash.clone(),
+                vec![format!("-- {} rows seeded into {}", rows, s.seed.table)],
+            ));
+        }
+        Ok(seeded)
+    }
+
+    /// Applies every seed whether it changed or not, bringing back rows changed since
+    async fn seed(&self, org: &str) -> Result<Vec<(String, usize)>, Box<dyn Error>> {
+        if self.seeds.is_empty() {
+            return Ok(vec![]);
+        }
+        let pool = self.pool.clone().ok_or("seed: no database pool")?;
         let conn = pool.conn().await?;
+        create_history(&conn, org).await?;
 
-        let _ = conn
-            .exec(format!(
-                "CREATE DATABASE IF NOT EXISTS {};",
-                org.clone().into()
-            ))
-            .await;
+        let mut res = vec![];
+        for s in &self.seeds {
+            let rows = s.apply(&conn, org).await?;
+            record_migration(
+                &conn,
+                org,
+                &s.history_name(),
+                &s.hash,
+                &[format!("-- {} rows seeded into {}", rows, s.seed.table)],
+            )
+            .await?;
+            res.push((s.name.clone(), rows));
+        }
+        Ok(res)
+    }
 
+    /// Planned steps of every table, tables already migrated to their DDL have none
+    async fn plan_steps(&self, org: &str) -> Result<Vec<(String, Vec<Step>)>, Box<dyn Error>> {
+        let pool = self.pool.clone().ok_or("migration: no database pool")?;
+        let conn = pool.conn().await?;
+        // nothing is applied yet when the history table is missing
+        let applied = applied_hashes(&conn, org).await.unwrap_or_default();
+
+        let mut res = vec![];
         for t in &self.tables {
-            migrate_table(t, pool.clone(), org.clone()).await?;
+            let steps = if applied.get(&t.ddl.name) == Some(&t.hash) {
+                vec![]
+            } else {
+                plan_migration(&t.ddl, &t.keys, &conn, org).await?
+            };
+            res.push((t.ddl.name.clone(), steps));
         }
+        Ok(res)
+    }
 
-        Ok(())
+    pub async fn plan(&self, org: &str) -> Result<Vec<TablePlan>, Box<dyn Error>> {
+        Ok(self
+            .plan_steps(org)
+            .await?
+            .into_iter()
+            .map(|(table, steps)| TablePlan {
+                table,
+                destructive: steps
+                    .iter()
+                    .filter_map(|s| s.destructive.as_ref().map(|d| d.to_string()))
+                    .collect(),
+                statements: steps.into_iter().map(|s| s.sql).collect(),
+            })
+            .collect())
     }
 }
 
@@ -53,10 +212,8 @@ impl Sql {
         Self { modules: vec![] }
     }
 
-    pub async fn load_db_conn(
-        instance: Instance,
-        org: String,
-    ) -> Result<DatabendPool, Box<dyn Error>> {
+    /// Url of the module's database, from its `riwaq_settings_db_conn` export or `DB_URL`
+    pub fn db_url(instance: &Instance, org: &str) -> Result<String, Box<dyn Error>> {
         #[derive(Deserialize)]
         struct DbConn {
             url: String,
@@ -83,16 +240,20 @@ impl Sql {
                 serde_json::from_str::<DbConn>(&res)?
             }
             Err(_) => DbConn {
-                url: std::env::var("DB_URL")?
-                    .to_string()
-                    .replace("{{org}}", &org),
+                url: std::env::var("DB_URL")?.to_string().replace("{{org}}", org),
             },
         };
 
-        Ok(Databend::init(DatabendConnParams::new(db_conn.url))?)
+        Ok(db_conn.url)
     }
 
-    pub async fn load_ddl(instance: Instance, org: String) -> Result<SqlModule, Box<dyn Error>> {
+    /// Tables, seeds and database pool of a module, the pool opened for the same url in `pools`
+    /// is reused when there is one. Tables whose DDL can't be read are skipped with a warning.
+    pub async fn load_ddl(
+        instance: Instance,
+        org: String,
+        pools: &HashMap<String, AnyPool>,
+    ) -> Result<SqlModule, Box<dyn Error>> {
         let handlers_metadata = instance
             .exports
             .iter()
@@ -105,23 +266,17 @@ impl Sql {
             .into_iter()
             .filter_map(|handler_metadata| {
                 if let Extern::Function(metadata_f) = handler_metadata.1 {
-                    (|| -> Result<TableDDL, Box<dyn Error>> {
-                        let ptr = metadata_f.call(&[])?;
-
-                        let memory_view: MemoryView<u8> = memory.view();
-                        let mut data: Vec<u8> = vec![];
-                        for v in memory_view[(ptr[0].unwrap_i32() as _)..].iter() {
-                            let v = v.get();
-                            if v == b'\0' {
-                                break;
-                            }
-                            data.push(v);
-                        }
-
-                        let res = String::from_utf8_lossy(data.as_slice());
+                    let name = handler_metadata.0.trim_start_matches("riwaq_table_ddl_");
+                    (|| -> Result<SqlTable, Box<dyn Error>> {
+                        let res = export_str(metadata_f, memory)?;
 
-                        serde_json::from_str::<TableDDL>(&res).map_err(|e| e.into())
+                        Ok(SqlTable {
+                            ddl: serde_json::from_str::<TableDDL>(&res)?,
+                            keys: serde_json::from_str::<TableKeys>(&res)?,
+                            hash: format!("{:x}", Sha256::digest(res.as_bytes())),
+                        })
                     })()
+                    .map_err(|e| tracing::warn!(org = %org, "table {} skipped: {}", name, e))
                     .ok()
                 } else {
                     None
@@ -129,19 +284,259 @@ impl Sql {
             })
             .collect();
 
+        let seeds = instance
+            .exports
+            .iter()
+            .filter_map(|(name, e)| match (name.strip_prefix("riwaq_seed_"), e) {
+                (Some(name), Extern::Function(f)) => Some((name, f)),
+                _ => None,
+            })
+            .filter_map(|(name, f)| {
+                (|| -> Result<SqlSeed, Box<dyn Error>> {
+                    let res = export_str(f, memory)?;
+                    Ok(SqlSeed {
+                        name: name.to_string(),
+                        seed: serde_json::from_str::<Seed>(&res)?,
+                        hash: format!("{:x}", Sha256::digest(res.as_bytes())),
+                    })
+                })()
+                .map_err(|e| tracing::warn!(org = %org, "seed {} skipped: {}", name, e))
+                .ok()
+            })
+            .collect();
+
+        let db_url = Self::db_url(&instance, &org).ok();
+        let pool = match &db_url {
+            Some(url) => match pools.get(url) {
+                Some(pool) => Some(pool.clone()),
+                None => Some(AnyDB::init(AnyConnParams::new(url.clone(), org))?),
+            },
+            None => None,
+        };
         Ok(SqlModule {
-            tables: tables,
-            pool: Self::load_db_conn(instance, org).await.ok(),
+            tables,
+            seeds,
+            db_url,
+            pool,
         })
     }
 
+    /// Applies the seeds of every module again, see [`SqlModule::seed`]
+    pub async fn seed(&self, org: &str) -> Result<Vec<(String, usize)>, Box<dyn Error>> {
+        let mut res = vec![];
+        for m in &self.modules {
+            res.extend(m.seed(org).await?);
+        }
+        Ok(res)
+    }
+
+    pub async fn plan(&self, org: &str) -> Result<Vec<TablePlan>, Box<dyn Error>> {
+        let mut res = vec![];
+        for m in &self.modules {
+            res.extend(m.plan(org).await?);
+        }
+        Ok(res)
+    }
+
+    /// Migrates every module, refusing upfront when a module would lose data the org's
+    /// [`DestructivePolicy`] doesn't allow, or when a databend migration would run destructive
+    /// steps along with others. Modules sharing a database migrate on one connection, in a
+    /// transaction rolled back when a statement fails on postgres and sqlite. Seeds are applied
+    /// in it once the tables of every database are migrated. Databend can't roll back DDL, the
+    /// steps it ran are reverted one by one instead and the error lists what became of them.
     pub async fn migrate<S>(self, org: S) -> Result<(), Box<dyn Error>>
     where
         S: Into<String> + Clone,
     {
-        for m in self.modules {
-            m.migrate(org.clone()).await?
+        let org: String = org.into();
+        let policy = DestructivePolicy::from_env(&org);
+
+        // modules migrate along with the ones using the same database
+        let mut dbs: Vec<(Option<&str>, Vec<&SqlModule>)> = vec![];
+        for m in &self.modules {
+            match dbs.iter_mut().find(|(url, _)| *url == m.db_url.as_deref()) {
+                Some((_, modules)) => modules.push(m),
+                None => dbs.push((m.db_url.as_deref(), vec![m])),
+            }
+        }
+
+        let mut refused = vec![];
+        let mut mixed = vec![];
+        for (_, modules) in &dbs {
+            let mut planned = vec![];
+            for m in modules {
+                for (table, steps) in m.plan_steps(&org).await? {
+                    for step in steps {
+                        match &step.destructive {
+                            Some(d) if !policy.allows(d) => {
+                                refused.push(format!("{}.{}: {} ({})", org, table, d, step.sql))
+                            }
+                            _ => {}
+                        }
+                        planned.push((table.clone(), step));
+                    }
+                }
+            }
+            let databend = modules
+                .iter()
+                .any(|m| m.pool.as_ref().map(|p| p.dialect()) == Some(Dialect::Databend));
+            if databend && planned.len() > 1 {
+                mixed.extend(planned.iter().filter_map(|(table, step)| {
+                    let d = step.destructive.as_ref()?;
+                    Some(format!("{}.{}: {} ({})", org, table, d, step.sql))
+                }));
+            }
+        }
+        if !refused.is_empty() {
+            tracing::warn!(org = %org, "destructive migration refused:\n{}", refused.join("\n"));
+            return Err(format!(
+                "refusing destructive migration, allow it with DB_ALLOW_DESTRUCTIVE_{}:\n{}",
+                org.to_uppercase().replace('-', "_"),
+                refused.join("\n")
+            )
+            .into());
+        }
+        if !mixed.is_empty() {
+            tracing::warn!(org = %org, "mixed destructive migration refused:\n{}", mixed.join("\n"));
+            return Err(format!(
+                "refusing destructive migration along with other changes, databend can't roll \
+                it back: deploy these changes on their own\n{}",
+                mixed.join("\n")
+            )
+            .into());
+        }
+
+        let mut migrations = vec![];
+        let res = async {
+            for (_, modules) in dbs {
+                let pool = modules[0]
+                    .pool
+                    .clone()
+                    .ok_or("migration: no database pool")?;
+                let migration = DbMigration::begin(&pool, modules).await?;
+                migrations.push(migration);
+                let migration = migrations.last_mut().unwrap();
+                migration.run(&org).await?;
+            }
+            for migration in migrations.iter_mut() {
+                migration.seed(&org).await?;
+            }
+            Ok::<(), Box<dyn Error>>(())
+        }
+        .await
+        .map_err(|e| e.to_string());
+
+        if let Err(e) = res {
+            let mut report = vec![];
+            for migration in migrations.into_iter().rev() {
+                report.extend(migration.revert().await);
+            }
+            tracing::warn!(org = %org, "migration failed: {}", e);
+            return Err(format!(
+                "migration of org '{}' failed, the previous version stays active: {}\n{}",
+                org,
+                e,
+                report.join("\n")
+            )
+            .into());
+        }
+
+        for migration in migrations {
+            migration.finish(&org).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Migration of the modules sharing a database, run on a connection of its own
+struct DbMigration<'a> {
+    modules: Vec<&'a SqlModule>,
+    conn: AnyConn,
+    /// whether the migration runs in a transaction, databend doesn't roll back DDL
+    tx: bool,
+    /// steps that ran, in execution order
+    applied: Vec<Step>,
+    /// migrated tables and applied seeds, recorded once every module migrated
+    migrated: Vec<(String, String, Vec<String>)>,
+}
+
+impl<'a> DbMigration<'a> {
+    async fn begin(pool: &AnyPool, modules: Vec<&'a SqlModule>) -> Result<Self, Box<dyn Error>> {
+        let conn = pool.tx_conn().await?;
+        let tx = conn.dialect().transactional_ddl();
+        if tx {
+            conn.exec("BEGIN").await?;
+        }
+        Ok(Self {
+            modules,
+            conn,
+            tx,
+            applied: vec![],
+            migrated: vec![],
+        })
+    }
+
+    async fn run(&mut self, org: &str) -> Result<(), Box<dyn Error>> {
+        for m in &self.modules {
+            let migrated = m.migrate(&self.conn, org, &mut self.applied).await?;
+            self.migrated.extend(migrated);
         }
         Ok(())
     }
+
+    /// Applies the seeds of the modules, once the tables of every database are migrated
+    async fn seed(&mut self, org: &str) -> Result<(), Box<dyn Error>> {
+        let mut seeded = vec![];
+        for m in &self.modules {
+            seeded.extend(m.apply_seeds(&self.conn, org, &self.migrated).await?);
+        }
+        self.migrated.extend(seeded);
+        Ok(())
+    }
+
+    /// Records the migrated tables and seeds and commits them along with the migration
+    async fn finish(self, org: &str) -> Result<(), Box<dyn Error>> {
+        for (name, hash, stmts) in &self.migrated {
+            record_migration(&self.conn, org, name, hash, stmts).await?;
+        }
+        if self.tx {
+            self.conn.exec("COMMIT").await?;
+        }
+        Ok(())
+    }
+
+    /// Rolls back the transaction, or else reverts the applied steps, describing the outcome
+    async fn revert(self) -> Vec<String> {
+        if !self.tx {
+            return rollback(&self.conn, self.applied).await;
+        }
+        match self.conn.exec("ROLLBACK").await {
+            Ok(_) => self
+                .applied
+                .iter()
+                .map(|s| format!("rolled back: {}", s.sql))
+                .collect(),
+            Err(e) => {
+                self.conn.discard();
+                vec![format!("rollback failed: {}", e)]
+            }
+        }
+    }
+}
+
+/// Calls an export returning a nul terminated string in the instance's memory
+fn export_str(f: &Function, memory: &Memory) -> Result<String, Box<dyn Error>> {
+    let ptr = f.call(&[])?;
+
+    let memory_view: MemoryView<u8> = memory.view();
+    let mut data: Vec<u8> = vec![];
+    for v in memory_view[(ptr[0].unwrap_i32() as _)..].iter() {
+        let v = v.get();
+        if v == b'\0' {
+            break;
+        }
+        data.push(v);
+    }
+
+    Ok(String::from_utf8_lossy(data.as_slice()).to_string())
 }
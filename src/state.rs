@@ -1,12 +1,22 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, error::Error, str::FromStr, sync::Arc};
 
 use async_graphql::{dynamic::Schema, EmptyMutation, EmptySubscription};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 
-use crate::server::QueryRoot;
+use crate::{server::QueryRoot, sql::driver::any::AnyPool};
 
 pub type StateOrgs = Arc<RwLock<HashMap<String, Org>>>;
 
+/// Events published by an org's modules through `ext_emit`, consumed by subscriptions.
+pub type OrgEvents = broadcast::Sender<serde_json::Value>;
+
+/// Reads an org scoped env var (`{key}_{ORG}`), falling back to the global `{key}`
+pub fn org_var(key: &str, org: &str) -> Option<String> {
+    std::env::var(format!("{}_{}", key, org.to_uppercase().replace('-', "_")))
+        .or_else(|_| std::env::var(key))
+        .ok()
+}
+
 #[derive(Debug, Clone)]
 pub enum StorageOrgBy {
     Bucket,
@@ -25,6 +35,42 @@ pub struct StorageConfig {
     pub org_by: StorageOrgBy,
 }
 
+impl StorageConfig {
+    /// Remote storage from `STORAGE_SCHEME` and `STORAGE_*` options, one bucket per org
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            kind: opendal::Scheme::from_str(
+                &std::env::var("STORAGE_SCHEME").unwrap_or("fs".to_string()),
+            )?,
+            opt: HashMap::from_iter(
+                std::env::vars()
+                    .filter_map(|(k, v)| {
+                        if k.starts_with("STORAGE_") {
+                            Some((k.trim_start_matches("STORAGE_").to_string(), v))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect::<Vec<(String, String)>>(),
+            ),
+            org_by: StorageOrgBy::Bucket,
+        })
+    }
+
+    /// Scopes the storage to the org's directory or bucket
+    pub fn for_org(&self, org: &str) -> Self {
+        let mut storage = self.clone();
+        match storage.org_by {
+            StorageOrgBy::Dir => storage
+                .opt
+                .get_mut("root")
+                .map(|v| *v = format!("{v}/{org}")),
+            StorageOrgBy::Bucket => storage.opt.get_mut("bucket").map(|v| *v = org.to_string()),
+        };
+        storage
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct Orgs {
     pub orgs: StateOrgs,
@@ -34,6 +80,10 @@ pub struct Orgs {
 #[derive(Debug)]
 pub struct Org {
     pub gql: Schema,
+    pub events: OrgEvents,
+    /// database pools of the org's modules by url, reused by `migrationPlan` and drained when
+    /// the org is reloaded
+    pub sql_pools: HashMap<String, AnyPool>,
 }
 
 #[derive(Default)]
@@ -1,2 +1,6 @@
+pub mod wasm_cache;
 pub mod wasm_helper;
 pub mod wasm_loader;
+pub mod wasm_pool;
+pub mod wasm_session;
+pub mod wasm_tunables;
@@ -0,0 +1,108 @@
+use std::{
+    env,
+    error::Error,
+    fs::{self, DirBuilder, OpenOptions},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use sha2::{Digest, Sha256};
+use wasmer::{Module, Store};
+
+use super::wasm_pool::PoolConfig;
+
+/// Temp files written by this process so far, naming the next one
+static TMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Directory holding serialized modules, `WASM_CACHE_DIR` or `riwaq` in the user's cache dir.
+/// Loaded artifacts are executed, so it's created private to the current user and refused when
+/// someone else could write to it.
+fn cache_dir() -> Result<PathBuf, Box<dyn Error>> {
+    let dir = match env::var_os("WASM_CACHE_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .ok_or("no cache dir, set WASM_CACHE_DIR")?
+            .join("riwaq"),
+    };
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+
+    let meta = fs::metadata(&dir)?;
+    // SAFETY: geteuid has no preconditions and can't fail
+    if meta.uid() != unsafe { libc::geteuid() } || meta.mode() & 0o022 != 0 {
+        return Err(format!("{} is writable by other users", dir.display()).into());
+    }
+    Ok(dir)
+}
+
+/// The compiled artifact depends on the wasm bytes, the compiler, the wasmer version, and the
+/// metering and memory settings baked into it at compile time.
+fn cache_key(wasm: &[u8], compiler: &str, config: &PoolConfig) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(wasm);
+    hasher.update(compiler.as_bytes());
+    hasher.update(wasmer::VERSION.as_bytes());
+    hasher.update(config.fuel.to_le_bytes());
+    hasher.update(config.max_memory_pages.to_le_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Loads the module from the on-disk cache, compiling and caching it on a miss
+pub fn load_module(
+    store: &Store,
+    wasm: &[u8],
+    compiler: &str,
+    config: &PoolConfig,
+) -> Result<Module, Box<dyn Error>> {
+    let dir = match cache_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            tracing::warn!("modules aren't cached: {}", e);
+            return Ok(Module::new(store, wasm)?);
+        }
+    };
+    let path = dir.join(format!("{}.bin", cache_key(wasm, compiler, config)));
+
+    if let Ok(artifact) = fs::read(&path) {
+        // SAFETY: cached artifacts are only written below by `Module::serialize`, under a key
+        // pinning the wasmer version and compiler that produced them
+        match unsafe { Module::deserialize(store, &artifact) } {
+            Ok(module) => return Ok(module),
+            Err(e) => tracing::warn!("cached module {} can't be read: {}", path.display(), e),
+        }
+    }
+
+    let module = Module::new(store, wasm)?;
+
+    // a failing cache write only costs a recompilation next time
+    if let Err(e) = (|| -> Result<(), Box<dyn Error>> {
+        // unique to the writer, concurrent loads of the same module each rename a whole file
+        let tmp = path.with_extension(format!(
+            "{}.{}.tmp",
+            process::id(),
+            TMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let artifact = module.serialize()?;
+        let written = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)
+            .and_then(|mut f| f.write_all(&artifact));
+        match written.and_then(|_| fs::rename(&tmp, &path)) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                Err(e.into())
+            }
+        }
+    })() {
+        tracing::warn!("module can't be cached at {}: {}", path.display(), e);
+    }
+
+    Ok(module)
+}
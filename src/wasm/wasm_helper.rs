@@ -1,140 +1,303 @@
-use std::{error::Error, ops::Index};
+use std::{
+    error::Error,
+    future::Future,
+    ops::{Deref, Index},
+};
 
+use async_graphql::futures_util::{
+    future::{select, Either},
+    StreamExt, TryStreamExt,
+};
+use serde::Serialize;
 use serde_json::{json, Value};
-use wasmer::{Exports, MemoryView, NativeFunc, WasmPtr};
+use tokio::sync::MutexGuard;
+use wasmer::{Exports, MemoryView, NativeFunc, RuntimeError, WasmPtr};
 
-use crate::sql::driver::model::{Conn, Pool, SQLFilter};
+use crate::sql::driver::{
+    any::{AnyConn, AnyPool},
+    bind::CustomSql,
+    error::SqlError,
+    guard::check_tenant,
+    model::{Conn, Pool, SQLFilter},
+};
 
-use super::wasm_loader::RiwaqEnv;
+use super::{
+    wasm_loader::RiwaqEnv,
+    wasm_session::{is_query, CursorRequest},
+};
 
-pub fn ext_sql_query(env: &RiwaqEnv, ptr: WasmPtr<u8>) -> WasmPtr<u8> {
-    let req_str = str_mem_read(&env.memory.get_ref().unwrap().view(), ptr.offset() as usize);
-    let request =
-        serde_json::from_str::<riwaq::sql::Select<SQLFilter>>(Box::leak(req_str.into_boxed_str()))
-            .unwrap();
+/// Connection of the handler's open transaction, or a pooled one outside of transactions
+pub enum EnvConn<'a> {
+    Tx(MutexGuard<'a, Option<AnyConn>>),
+    Pooled(AnyConn),
+}
+
+impl Deref for EnvConn<'_> {
+    type Target = AnyConn;
+
+    fn deref(&self) -> &AnyConn {
+        match self {
+            // only built around an open transaction
+            EnvConn::Tx(tx) => tx.as_ref().unwrap(),
+            EnvConn::Pooled(conn) => conn,
+        }
+    }
+}
 
-    let pool = env.db_pool.to_owned();
-    let res = tokio::task::block_in_place(move || {
+async fn env_pool(env: &RiwaqEnv) -> Result<AnyPool, SqlError> {
+    Ok(env
+        .db_pool
+        .read()
+        .await
+        .to_owned()
+        .ok_or("no database is configured for this module")?)
+}
+
+async fn env_conn(env: &RiwaqEnv) -> Result<EnvConn<'_>, SqlError> {
+    let tx = env.sql.tx.lock().await;
+    if tx.is_some() {
+        return Ok(EnvConn::Tx(tx));
+    }
+    drop(tx);
+    Ok(EnvConn::Pooled(env_pool(env).await?.conn().await?))
+}
+
+/// Runs a host call on the handler's thread, giving up once the handler is cancelled by its
+/// timeout so the query in flight is dropped
+fn host_call<T>(
+    env: &RiwaqEnv,
+    f: impl Future<Output = Result<T, SqlError>>,
+) -> Result<T, SqlError> {
+    tokio::task::block_in_place(move || {
         tokio::runtime::Handle::current().block_on(async move {
-            let pool = pool.read().await.to_owned().unwrap();
-            let conn = pool.conn().await.unwrap();
-            conn.all(request).await.map_err(|e| e.to_string())
+            match select(Box::pin(f), Box::pin(env.sql.cancelled())).await {
+                Either::Left((res, _)) => res,
+                Either::Right(_) => {
+                    Err(SqlError::new("handler timed out, its query was cancelled"))
+                }
+            }
         })
+    })
+}
+
+/// Rows keyed by their column names
+fn keyed(cols: &[String], rows: Vec<Vec<Value>>) -> Vec<Value> {
+    rows.into_iter()
+        .map(|row| Value::Object(cols.iter().cloned().zip(row).collect()))
+        .collect()
+}
+
+fn check_rows(rows: usize, max_rows: usize) -> Result<(), SqlError> {
+    if rows > max_rows {
+        return Err(SqlError::new(format!(
+            "query returned more than {} rows (DB_MAX_ROWS), read it with a cursor",
+            max_rows
+        )));
+    }
+    Ok(())
+}
+
+/// Rows of the query `sql`, streamed up to the first row past `max_rows`
+async fn first_rows(
+    conn: &AnyConn,
+    sql: String,
+    max_rows: usize,
+) -> Result<Vec<Vec<Value>>, SqlError> {
+    let rows = conn
+        .stream(sql)
+        .await?
+        .take(max_rows + 1)
+        .try_collect::<Vec<Vec<Value>>>()
+        .await?;
+    check_rows(rows.len(), max_rows)?;
+    Ok(rows)
+}
+
+/// Runs a query of at most `DB_MAX_ROWS` rows, rows are streamed so those past the limit are
+/// never read. Like custom SQL it may only read the org's tables.
+pub fn ext_sql_query(env: &RiwaqEnv, ptr: WasmPtr<u8>) -> Result<WasmPtr<u8>, RuntimeError> {
+    let req_str = str_mem_read(&env.memory.get_ref().unwrap().view(), ptr.offset() as usize);
+    let request = serde_json::from_str::<riwaq::sql::Select<SQLFilter>>(&req_str);
+
+    let res = host_call(env, async move {
+        let request = request?;
+        let conn = env_conn(env).await?;
+        let cols = request
+            .cols
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<String>>();
+        let sql = request.to_string();
+        check_tenant(&sql, &env.org, conn.dialect())?;
+        let rows = first_rows(&conn, sql, env.max_rows).await?;
+        Ok(keyed(&cols, rows))
     });
-    let s = res
-        .map(|r| {
-            serde_json::to_string(&serde_json::json!({
-                "ok": true,
-                "data": r
-            }))
-            .unwrap()
-        })
-        .unwrap_or_else(|e| {
-            serde_json::to_string(&serde_json::json!({
-                "ok": false,
-                "msg": e
-            }))
-            .unwrap()
-        });
-    let p = env
-        .str_malloc
-        .get_ref()
-        .unwrap()
-        .call(s.len() as _)
-        .map_err(|e| dbg!(e))
-        .unwrap();
-    str_mem_write(&env.memory.get_ref().unwrap().view(), p, s).unwrap();
-    p
-}
-
-pub fn ext_sql_exec(env: &RiwaqEnv, ptr: WasmPtr<u8>) -> WasmPtr<u8> {
+    write_envelope(env, res)
+}
+
+pub fn ext_sql_exec(env: &RiwaqEnv, ptr: WasmPtr<u8>) -> Result<WasmPtr<u8>, RuntimeError> {
     let req_str = str_mem_read(&env.memory.get_ref().unwrap().view(), ptr.offset() as usize);
-    let request =
-        serde_json::from_str::<riwaq::sql::SQLRequest<SQLFilter>>(req_str.as_str()).unwrap();
+    let request = serde_json::from_str::<riwaq::sql::SQLRequest<SQLFilter>>(req_str.as_str());
 
-    let res = tokio::task::block_in_place(move || {
-        tokio::runtime::Handle::current().block_on(async move {
-            let pool = env.db_pool.to_owned().read().await.to_owned().unwrap();
-            let conn = pool.conn().await.unwrap();
-            conn.exec(request).await.map_err(|e| e.to_string())
-        })
+    let res = host_call(env, async move {
+        let sql = request?.to_string();
+        let conn = env_conn(env).await?;
+        check_tenant(&sql, &env.org, conn.dialect())?;
+        conn.exec(sql).await
     });
-    let s = res
-        .map(|r| {
-            serde_json::to_string(&serde_json::json!({
-                "ok": true,
-                "data": r
-            }))
-            .unwrap()
-        })
-        .unwrap_or_else(|e| {
-            serde_json::to_string(&serde_json::json!({
-                "ok": false,
-                "msg": e
-            }))
-            .unwrap()
-        });
+    write_envelope(env, res)
+}
+
+/// Runs a custom query given as plain SQL or as a `{sql, params}` envelope whose parameters
+/// are bound as escaped literals, statements reaching outside the org's database are refused.
+/// Queries are limited to `DB_MAX_ROWS` rows as they're streamed, other statements returning
+/// more fail once read.
+pub fn ext_custom_sql_query(env: &RiwaqEnv, ptr: WasmPtr<u8>) -> Result<WasmPtr<u8>, RuntimeError> {
+    let req_str = str_mem_read(&env.memory.get_ref().unwrap().view(), ptr.offset() as usize);
+    let request = CustomSql::parse(&req_str);
 
-    let p = env
-        .str_malloc
-        .get_ref()
-        .unwrap()
-        .call(s.len() as _)
-        .map_err(|e| dbg!(e))
-        .unwrap();
-    str_mem_write(&env.memory.get_ref().unwrap().view(), p, s).unwrap();
-    p
+    let res = host_call(env, async move {
+        let conn = env_conn(env).await?;
+        let sql = request.bind(conn.dialect())?;
+        check_tenant(&sql, &env.org, conn.dialect())?;
+        if is_query(&sql) {
+            return first_rows(&conn, sql, env.max_rows).await;
+        }
+        let rows = conn.custom_query(sql).await?;
+        check_rows(rows.len(), env.max_rows)?;
+        Ok(rows)
+    });
+    write_envelope(env, res)
 }
 
-pub fn ext_custom_sql_query(env: &RiwaqEnv, ptr: WasmPtr<u8>) -> WasmPtr<u8> {
+/// Runs a custom statement, accepts the same input as [`ext_custom_sql_query`]
+pub fn ext_custom_sql_exec(env: &RiwaqEnv, ptr: WasmPtr<u8>) -> Result<WasmPtr<u8>, RuntimeError> {
     let req_str = str_mem_read(&env.memory.get_ref().unwrap().view(), ptr.offset() as usize);
-    let request = Box::leak(req_str.into_boxed_str());
+    let request = CustomSql::parse(&req_str);
 
-    let pool = env.db_pool.to_owned();
-    let res = tokio::task::block_in_place(move || {
-        tokio::runtime::Handle::current().block_on(async move {
-            let pool = pool.read().await.to_owned().unwrap();
-            let conn = pool.conn().await.unwrap();
-            conn.custom_query(request.to_string()).await.map_err(|e| e.to_string())
-        })
+    let res = host_call(env, async move {
+        let conn = env_conn(env).await?;
+        let sql = request.bind(conn.dialect())?;
+        check_tenant(&sql, &env.org, conn.dialect())?;
+        conn.exec(sql).await
     });
-    let s = res
-        .map(|r| {
-            serde_json::to_string(&serde_json::json!({
-                "ok": true,
-                "data": r
-            }))
-            .unwrap()
-        })
-        .unwrap_or_else(|e| {
-            serde_json::to_string(&serde_json::json!({
-                "ok": false,
-                "msg": e
-            }))
-            .unwrap()
-        });
-    let p = env
-        .str_malloc
-        .get_ref()
-        .unwrap()
-        .call(s.len() as _)
-        .map_err(|e| dbg!(e))
-        .unwrap();
-    str_mem_write(&env.memory.get_ref().unwrap().view(), p, s).unwrap();
-    p
-}
-
-pub fn ext_custom_sql_exec(env: &RiwaqEnv, ptr: WasmPtr<u8>) -> WasmPtr<u8> {
+    write_envelope(env, res)
+}
+
+/// Opens a transaction pinned to one connection, the following sql calls of the handler run
+/// in it until `ext_sql_commit` or `ext_sql_rollback`
+pub fn ext_sql_begin(env: &RiwaqEnv) -> Result<WasmPtr<u8>, RuntimeError> {
+    let res = host_call(env, async move {
+        let mut tx = env.sql.tx.lock().await;
+        if tx.is_some() {
+            return Err(SqlError::new("a transaction is already open"));
+        }
+        let conn = env_pool(env).await?.tx_conn().await?;
+        conn.exec("BEGIN").await?;
+        *tx = Some(conn);
+        Ok(())
+    });
+    write_envelope(env, res)
+}
+
+pub fn ext_sql_commit(env: &RiwaqEnv) -> Result<WasmPtr<u8>, RuntimeError> {
+    let res = host_call(env, env.sql.end_tx("COMMIT"));
+    write_envelope(env, res)
+}
+
+pub fn ext_sql_rollback(env: &RiwaqEnv) -> Result<WasmPtr<u8>, RuntimeError> {
+    let res = host_call(env, env.sql.end_tx("ROLLBACK"));
+    write_envelope(env, res)
+}
+
+/// Opens a cursor over a query, accepts the same input as [`ext_sql_query`] and returns the
+/// cursor id, rows are then read in batches with `ext_sql_cursor_next`
+pub fn ext_sql_cursor_open(env: &RiwaqEnv, ptr: WasmPtr<u8>) -> Result<WasmPtr<u8>, RuntimeError> {
     let req_str = str_mem_read(&env.memory.get_ref().unwrap().view(), ptr.offset() as usize);
-    let request = req_str.as_str();
+    let request = serde_json::from_str::<riwaq::sql::Select<SQLFilter>>(&req_str);
 
-    let res = tokio::task::block_in_place(move || {
-        tokio::runtime::Handle::current().block_on(async move {
-            let pool = env.db_pool.to_owned().read().await.to_owned().unwrap();
-            let conn = pool.conn().await.unwrap();
-            conn.exec(request).await.map_err(|e| e.to_string())
+    let res = host_call(env, async move {
+        let request = request?;
+        let sql = request.to_string();
+        let pool = env_pool(env).await?;
+        check_tenant(&sql, &env.org, pool.dialect())?;
+        let cols = request.cols.iter().map(|c| c.to_string()).collect();
+        env.sql.open_cursor(pool, sql, Some(cols)).await
+    });
+    write_envelope(env, res)
+}
+
+/// Opens a cursor over a custom query, accepts the same input as [`ext_custom_sql_query`]
+pub fn ext_custom_sql_cursor_open(
+    env: &RiwaqEnv,
+    ptr: WasmPtr<u8>,
+) -> Result<WasmPtr<u8>, RuntimeError> {
+    let req_str = str_mem_read(&env.memory.get_ref().unwrap().view(), ptr.offset() as usize);
+    let request = CustomSql::parse(&req_str);
+
+    let res = host_call(env, async move {
+        let pool = env_pool(env).await?;
+        let sql = request.bind(pool.dialect())?;
+        check_tenant(&sql, &env.org, pool.dialect())?;
+        if !is_query(&sql) {
+            return Err(SqlError::new("cursors only read queries"));
+        }
+        env.sql.open_cursor(pool, sql, None).await
+    });
+    write_envelope(env, res)
+}
+
+/// Next batch of a cursor, `{"id": .., "size": ..}`, an empty batch once all rows were read
+pub fn ext_sql_cursor_next(env: &RiwaqEnv, ptr: WasmPtr<u8>) -> Result<WasmPtr<u8>, RuntimeError> {
+    let req_str = str_mem_read(&env.memory.get_ref().unwrap().view(), ptr.offset() as usize);
+    let request = serde_json::from_str::<CursorRequest>(&req_str);
+
+    let res = host_call(env, async move {
+        let request = request?;
+        let mut cursors = env.sql.cursors.lock().await;
+        let cursor = cursors
+            .get_mut(&request.id)
+            .ok_or("no cursor is open with this id")?;
+        let size = request.size.unwrap_or(env.max_rows).clamp(1, env.max_rows);
+        let rows = cursor
+            .rows
+            .by_ref()
+            .take(size)
+            .try_collect::<Vec<Vec<Value>>>()
+            .await?;
+        Ok(match &cursor.cols {
+            Some(cols) => keyed(cols, rows),
+            None => rows.into_iter().map(Value::from).collect(),
         })
     });
+    write_envelope(env, res)
+}
+
+/// Closes a cursor, `{"id": ..}`, cursors left open are closed once the handler returns
+pub fn ext_sql_cursor_close(env: &RiwaqEnv, ptr: WasmPtr<u8>) -> Result<WasmPtr<u8>, RuntimeError> {
+    let req_str = str_mem_read(&env.memory.get_ref().unwrap().view(), ptr.offset() as usize);
+    let request = serde_json::from_str::<CursorRequest>(&req_str);
+
+    let res = host_call(env, async move {
+        let request = request?;
+        env.sql
+            .cursors
+            .lock()
+            .await
+            .remove(&request.id)
+            .map(|_| ())
+            .ok_or_else(|| SqlError::new("no cursor is open with this id"))
+    });
+    write_envelope(env, res)
+}
+
+/// Writes the `{"ok": true, "data"}` or `{"ok": false, "msg", "code"}` envelope of `res` to
+/// the instance's memory, `code` is the database's error code when it reported one. Failing
+/// to allocate it traps the handler, which can't be handed the result.
+fn write_envelope<T: Serialize>(
+    env: &RiwaqEnv,
+    res: Result<T, SqlError>,
+) -> Result<WasmPtr<u8>, RuntimeError> {
     let s = res
         .map(|r| {
             serde_json::to_string(&serde_json::json!({
@@ -146,20 +309,28 @@ pub fn ext_custom_sql_exec(env: &RiwaqEnv, ptr: WasmPtr<u8>) -> WasmPtr<u8> {
         .unwrap_or_else(|e| {
             serde_json::to_string(&serde_json::json!({
                 "ok": false,
-                "msg": e
+                "msg": e.msg,
+                "code": e.code
             }))
             .unwrap()
         });
+    let p = env.str_malloc.get_ref().unwrap().call(s.len() as _)?;
+    str_mem_write(&env.memory.get_ref().unwrap().view(), p, s)
+        .map_err(|e| RuntimeError::new(e.to_string()))?;
+    Ok(p)
+}
 
-    let p = env
-        .str_malloc
-        .get_ref()
-        .unwrap()
-        .call(s.len() as _)
-        .map_err(|e| dbg!(e))
-        .unwrap();
-    str_mem_write(&env.memory.get_ref().unwrap().view(), p, s).unwrap();
-    p
+pub fn ext_emit(env: &RiwaqEnv, ptr: WasmPtr<u8>) {
+    let event_str = str_mem_read(&env.memory.get_ref().unwrap().view(), ptr.offset() as usize);
+    match serde_json::from_str::<Value>(&event_str) {
+        Ok(event) => {
+            // sending only fails when no subscriber is listening
+            let _ = env.events.send(event);
+        }
+        Err(e) => {
+            tracing::warn!(org = %env.org, "invalid event dropped: {}", e);
+        }
+    }
 }
 
 pub fn call_wasm(
@@ -1,25 +1,29 @@
-use std::{env, error::Error, sync::Arc};
+use std::{collections::HashMap, env, error::Error, sync::Arc};
 
 use async_graphql::futures_util::TryStreamExt;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 use wasmer::{
-    imports, ChainableNamedResolver, Cranelift, Function, ImportObject, Instance, LazyInit, Memory,
-    Module, NativeFunc, Singlepass, Store, Universal, UniversalEngine, WasmPtr,
+    wasmparser::Operator, BaseTunables, CompilerConfig, Cranelift, LazyInit, Memory, NativeFunc,
+    Pages, Singlepass, Store, Target, Universal, UniversalEngine, WasmPtr,
 };
-use wasmer_wasi::{generate_import_object_from_env, WasiEnv, WasiState};
+use wasmer_middlewares::Metering;
 
 use crate::{
     gql::gql_loader::Gql,
     server::init_operator,
     sql::{
-        driver::databend::DatabendPool,
+        driver::any::AnyPool,
         sql_loader::{Sql, SqlModule},
     },
-    state::{Org, Orgs, StorageConfig, StorageOrgBy},
-    wasm::wasm_helper::str_mem_read,
+    state::{Org, OrgEvents, Orgs, StorageConfig},
 };
 
-use super::wasm_helper::{ext_custom_sql_exec, ext_custom_sql_query, ext_sql_exec, ext_sql_query};
+use super::{
+    wasm_cache::load_module,
+    wasm_pool::{InstancePool, PoolConfig},
+    wasm_session::SqlSession,
+    wasm_tunables::LimitingTunables,
+};
 
 #[derive(Clone, wasmer::WasmerEnv)]
 pub struct RiwaqEnv {
@@ -27,7 +31,101 @@ pub struct RiwaqEnv {
     pub memory: LazyInit<Memory>,
     #[wasmer(export)]
     pub str_malloc: LazyInit<NativeFunc<u64, WasmPtr<u8>>>,
-    pub db_pool: Arc<RwLock<Option<DatabendPool>>>,
+    /// custom sql is confined to this org's database
+    pub org: String,
+    pub db_pool: Arc<RwLock<Option<AnyPool>>>,
+    pub sql: Arc<SqlSession>,
+    /// rows a single query may return, larger results are read with cursors
+    pub max_rows: usize,
+    pub events: OrgEvents,
+}
+
+/// Store compiled by `WASM_COMPILER`, metered and memory capped with the org's limits
+pub fn org_store(pool_config: &PoolConfig) -> (&'static str, Store) {
+    // every operator costs one point, the budget itself is reset before each handler call
+    let metering = Arc::new(Metering::new(pool_config.fuel, |_: &Operator| -> u64 { 1 }));
+    let (compiler_name, compiler): (&'static str, UniversalEngine) = match env::var("WASM_COMPILER")
+        .unwrap_or("cranelift".to_string())
+        .as_str()
+    {
+        "singlepass" => {
+            let mut compiler = Singlepass::new();
+            compiler.push_middleware(metering);
+            ("singlepass", Universal::new(compiler).engine())
+        }
+        // "llvm" => Universal::new(LLVM::new()).engine(),
+        _ => {
+            let mut compiler = Cranelift::new();
+            compiler.push_middleware(metering);
+            ("cranelift", Universal::new(compiler).engine())
+        }
+    };
+    let store = Store::new_with_tunables(
+        &compiler,
+        LimitingTunables::new(
+            BaseTunables::for_target(&Target::default()),
+            Pages(pool_config.max_memory_pages),
+        ),
+    );
+    (compiler_name, store)
+}
+
+/// Reads every `.wasm` module found in the org's storage
+pub async fn read_modules(storage: &StorageConfig) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let op = init_operator(Arc::new(storage.clone()))?;
+
+    let mut modules = op
+        .scan("/")
+        .await
+        .map_err(|e| e.with_context("op", "error listing files"))
+        .inspect_err(|e| tracing::error!("{}", e))?;
+
+    let mut res = vec![];
+    while let Some(e) = modules
+        .try_next()
+        .await
+        .map_err(|e| e.with_context("op", "error getting next file"))
+        .inspect_err(|e| tracing::error!("{}", e))?
+    {
+        if e.name().starts_with(',') || !e.name().ends_with(".wasm") {
+            continue;
+        }
+        res.push(
+            op.read(e.path())
+                .await
+                .map_err(|e| e.with_context("op", "error reading file"))
+                .inspect_err(|e| tracing::error!("{}", e))?,
+        );
+    }
+    Ok(res)
+}
+
+/// Table definitions and database pools of an org's modules, nothing is migrated. Pools in
+/// `pools` are reused for the modules whose database url they were opened for.
+pub async fn load_sql(
+    org: &str,
+    modules: Vec<Vec<u8>>,
+    pools: &HashMap<String, AnyPool>,
+) -> Result<Sql, Box<dyn Error>> {
+    let pool_config = PoolConfig {
+        min: 1,
+        max: 1,
+        ..PoolConfig::from_env(org)
+    };
+    let (compiler_name, store) = org_store(&pool_config);
+
+    let mut sql = Sql::new();
+    for wasm in modules {
+        let module = load_module(&store, &wasm, compiler_name, &pool_config)?;
+        let pool = InstancePool::new(org, module, broadcast::channel(1).0, pool_config.clone())?;
+        let instance = pool.get().await?;
+        let sql_module = Sql::load_ddl((*instance).clone(), org.to_string(), pools)
+            .await
+            .map_err(|e| tracing::warn!(org = %org, "tables of a module skipped: {}", e))
+            .ok();
+        sql.modules.extend(sql_module);
+    }
+    Ok(sql)
 }
 
 impl Orgs {
@@ -39,140 +137,72 @@ impl Orgs {
     where
         S: Into<String> + Clone,
     {
-        let mut gql = Gql::new();
-        let mut sql = Sql::new();
-
-        let compiler: UniversalEngine = match env::var("WASM_COMPILER")
-            .unwrap_or("cranelift".to_string())
-            .as_str()
+        // keep the org's event channel across reloads so live subscriptions survive them
+        let events: OrgEvents;
         {
-            "singlepass" => Universal::new(Singlepass::new()).engine(),
-            // "llvm" => Universal::new(LLVM::new()).engine(),
-            _ => Universal::new(Cranelift::new()).engine(),
-        };
-        let store = Store::new(&compiler);
-
-        let mut storage = (*storage).clone();
-        match storage.org_by {
-            StorageOrgBy::Dir => storage
-                .opt
-                .get_mut("root")
-                .map(|v| *v = format!("{v}/{org}", org = org.clone().into())),
-            StorageOrgBy::Bucket => storage
-                .opt
-                .get_mut("bucket")
-                .map(|v| *v = org.clone().into()),
-        };
-
-        let op = init_operator(Arc::new(storage.clone()))?;
-
-        let mut modules = op
-            .scan("/")
-            .await
-            .map_err(|e| e.with_context("op", "error listing files"))
-            .map_err(|e| dbg!(e))?;
-
-        while let Some(e) = modules
-            .try_next()
-            .await
-            .map_err(|e| e.with_context("op", "error getting next file"))
-            .map_err(|e| dbg!(e))?
-        {
-            if e.name().starts_with(',') || !e.name().ends_with(".wasm") {
-                continue;
-            }
-            let path = e.path();
-            let res = op
-                .read(path)
+            events = self
+                .orgs
+                .read()
                 .await
-                .map_err(|e| e.with_context("op", "error reading file"))
-                .map_err(|e| dbg!(e))?;
+                .get::<String>(&org.clone().into())
+                .map(|o| o.events.clone())
+                .unwrap_or_else(|| broadcast::channel(1024).0);
+        }
 
-            let module = Module::new(&store, res).map_err(|e| dbg!(e))?;
+        let mut gql = Gql::new(events.clone());
+        let mut sql = Sql::new();
+        let org_name: String = org.clone().into();
+        let pool_config = PoolConfig::from_env(&org_name);
 
-            let objects = ImportObject::new();
+        let (compiler_name, store) = org_store(&pool_config);
 
-            let wasi_env = WasiEnv::new(WasiState::new("riwaq").build()?);
-            let objects = objects.chain_front(generate_import_object_from_env(
-                &store,
-                wasi_env.clone(),
-                wasmer_wasi::WasiVersion::Snapshot1,
-            ));
+        let modules = read_modules(&storage.for_org(&org_name)).await?;
+        for res in modules {
+            let module = load_module(&store, &res, compiler_name, &pool_config)?;
 
-            let mut riwaq_env = RiwaqEnv {
-                memory: LazyInit::new(),
-                str_malloc: LazyInit::new(),
-                db_pool: Arc::new(RwLock::new(None)),
-            };
+            let pool = InstancePool::new(&org_name, module, events.clone(), pool_config.clone())?;
+            let instance = pool.get().await?;
 
-            let objects = objects.chain_front(imports! {
-                "env" => {
-                    "riwaq_dbg" => Function::new_native_with_env(&store, riwaq_env.clone(), |env: &RiwaqEnv, ptr: WasmPtr<u8>| {
-                        println!(
-                            "{}",
-                            str_mem_read(&env.memory.get_ref().unwrap().view(), ptr.offset() as usize)
-                        );
-                    }),
-                    "ext_sql_exec" => Function::new_native_with_env(&store, riwaq_env.clone(), ext_sql_exec),
-                    "ext_sql_query" => Function::new_native_with_env(&store, riwaq_env.clone(), ext_sql_query),
-                    "ext_custom_sql_exec" => Function::new_native_with_env(&store, riwaq_env.clone(), ext_custom_sql_exec),
-                    "ext_custom_sql_query" => Function::new_native_with_env(&store, riwaq_env.clone(), ext_custom_sql_query)
-                }
-            });
-
-            let instance = Instance::new(&module, &objects).map_err(|e| dbg!(e))?;
-
-            riwaq_env
-                .memory
-                .initialize(instance.exports.get_memory("memory")?.to_owned());
-            riwaq_env.str_malloc.initialize(
-                instance
-                    .exports
-                    .get_native_function("str_malloc")?
-                    .to_owned(),
-            );
-            let sql_module = Sql::load_ddl(instance.clone(), org.clone().into())
+            let sql_module = Sql::load_ddl((*instance).clone(), org_name.clone(), &HashMap::new())
                 .await
+                .map_err(|e| tracing::warn!(org = %org_name, "tables of a module skipped: {}", e))
                 .ok();
             if let Some(SqlModule {
                 pool: Some(sql_pool),
                 ..
             }) = &sql_module
             {
-                let mut a = riwaq_env.db_pool.write().await;
+                let mut a = pool.db_pool.write().await;
                 *a = Some(sql_pool.clone());
             };
             if let Some(qm) = sql_module {
                 sql.modules.push(qm);
             };
 
-            gql = gql.load_handlers(instance.clone())?;
+            gql = gql.load_handlers(pool.clone(), &instance)?;
         }
 
+        let schema = gql
+            .build_schema()
+            .inspect_err(|e| tracing::error!(org = %org_name, "schema can't be built: {}", e))?;
         let o: (String, Org) = (
             org.clone().into(),
             Org {
-                gql: gql.build_schema().map_err(|e| dbg!(e))?,
+                gql: schema,
+                events,
+                sql_pools: sql
+                    .modules
+                    .iter()
+                    .filter_map(|m| Some((m.db_url.clone()?, m.pool.clone()?)))
+                    .collect(),
             },
         );
 
         sql.migrate(org.clone()).await?;
 
-        // let ex_pool;
-        // {
-        //     ex_pool = self
-        //         .orgs
-        //         .read()
-        //         .await
-        //         .get(&o.0)
-        //         .map_or(None, |org| org.sql_pool.as_ref().map(|p| p.clone()));
-        // }
-        {
-            self.orgs.write().await.insert(o.0, o.1);
-        }
-        // if let Some(p) = ex_pool {
-        //     let _ = p.disconnect().await;
-        // };
+        // the replaced org's pools are left to drain: handlers still running on its instances
+        // keep using them and their connections close once the last of them is dropped
+        self.orgs.write().await.insert(o.0, o.1);
         Ok(())
     }
 }
@@ -0,0 +1,312 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde_json::Value;
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+use wasmer::{
+    imports, ChainableNamedResolver, Function, ImportObject, Instance, LazyInit, Module, WasmPtr,
+};
+use wasmer_middlewares::metering::{get_remaining_points, set_remaining_points, MeteringPoints};
+use wasmer_wasi::{generate_import_object_from_env, WasiEnv, WasiState};
+
+use crate::{
+    sql::driver::any::AnyPool,
+    state::{org_var, OrgEvents},
+};
+
+use super::{
+    wasm_helper::{
+        call_wasm, ext_custom_sql_cursor_open, ext_custom_sql_exec, ext_custom_sql_query, ext_emit,
+        ext_sql_begin, ext_sql_commit, ext_sql_cursor_close, ext_sql_cursor_next,
+        ext_sql_cursor_open, ext_sql_exec, ext_sql_query, ext_sql_rollback, str_mem_read,
+    },
+    wasm_loader::RiwaqEnv,
+    wasm_session::SqlSession,
+    wasm_tunables::take_limit_hit,
+};
+
+pub type WasmPool = Arc<InstancePool>;
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// instances created upfront when the module is loaded
+    pub min: usize,
+    /// upper bound of instances alive at once, callers wait for a free one beyond it
+    pub max: usize,
+    /// metering points (wasm operators) a single handler call may consume
+    pub fuel: u64,
+    /// wall-clock bound of a single handler call
+    pub timeout: Duration,
+    /// linear memory cap of each instance, in 64KiB wasm pages
+    pub max_memory_pages: u32,
+    /// rows a single sql query may return to a handler, cursors read larger results
+    pub max_rows: usize,
+}
+
+impl PoolConfig {
+    /// Reads `WASM_POOL_MIN`, `WASM_POOL_MAX`, `WASM_FUEL_LIMIT`, `WASM_TIMEOUT_MS`,
+    /// `WASM_MAX_MEMORY_PAGES` and `DB_MAX_ROWS`, overridable per org (e.g. `WASM_POOL_MAX_ACME`)
+    pub fn from_env(org: &str) -> Self {
+        let min = org_var("WASM_POOL_MIN", org)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1);
+        let max = org_var("WASM_POOL_MAX", org)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(16)
+            .max(min)
+            .max(1);
+        let fuel = org_var("WASM_FUEL_LIMIT", org)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10_000_000_000);
+        let timeout = org_var("WASM_TIMEOUT_MS", org)
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(Duration::from_secs(30));
+        let max_memory_pages = org_var("WASM_MAX_MEMORY_PAGES", org)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(16384);
+        let max_rows = org_var("DB_MAX_ROWS", org)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10_000)
+            .max(1);
+        Self {
+            min,
+            max,
+            fuel,
+            timeout,
+            max_memory_pages,
+            max_rows,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HandlerError {
+    /// the handler exhausted its fuel budget or ran past its wall-clock timeout
+    Timeout,
+    /// the instance needed more memory than the org's page limit, when created or while running
+    MemoryLimit(String),
+    Failed(String),
+}
+
+impl Display for HandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandlerError::Timeout => write!(f, "handler exceeded its execution budget"),
+            HandlerError::MemoryLimit(msg) => {
+                write!(f, "handler reached its memory limit: {}", msg)
+            }
+            HandlerError::Failed(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Error for HandlerError {}
+
+/// Pre-instantiated instances of one module, each handler call checks out its own instance
+/// so concurrent requests never share linear memory.
+pub struct InstancePool {
+    org: String,
+    module: Module,
+    pub db_pool: Arc<RwLock<Option<AnyPool>>>,
+    events: OrgEvents,
+    idle: Mutex<Vec<(Instance, Arc<SqlSession>)>>,
+    permits: Arc<Semaphore>,
+    config: PoolConfig,
+}
+
+impl InstancePool {
+    pub fn new(
+        org: &str,
+        module: Module,
+        events: OrgEvents,
+        config: PoolConfig,
+    ) -> Result<WasmPool, Box<dyn Error>> {
+        let pool = Self {
+            org: org.to_string(),
+            module,
+            db_pool: Arc::new(RwLock::new(None)),
+            events,
+            idle: Mutex::new(vec![]),
+            permits: Arc::new(Semaphore::new(config.max)),
+            config,
+        };
+        for _ in 0..pool.config.min {
+            let idle = pool.instantiate()?;
+            pool.idle.lock().unwrap().push(idle);
+        }
+        Ok(Arc::new(pool))
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    fn instantiate(&self) -> Result<(Instance, Arc<SqlSession>), Box<dyn Error>> {
+        let store = self.module.store();
+        let objects = ImportObject::new();
+
+        let wasi_env = WasiEnv::new(WasiState::new("riwaq").build()?);
+        let objects = objects.chain_front(generate_import_object_from_env(
+            store,
+            wasi_env,
+            wasmer_wasi::WasiVersion::Snapshot1,
+        ));
+
+        let riwaq_env = RiwaqEnv {
+            memory: LazyInit::new(),
+            str_malloc: LazyInit::new(),
+            org: self.org.clone(),
+            db_pool: self.db_pool.clone(),
+            sql: Arc::new(SqlSession::default()),
+            max_rows: self.config.max_rows,
+            events: self.events.clone(),
+        };
+        let sql = riwaq_env.sql.clone();
+
+        let objects = objects.chain_front(imports! {
+            "env" => {
+                "riwaq_dbg" => Function::new_native_with_env(store, riwaq_env.clone(), |env: &RiwaqEnv, ptr: WasmPtr<u8>| {
+                    println!(
+                        "{}",
+                        str_mem_read(&env.memory.get_ref().unwrap().view(), ptr.offset() as usize)
+                    );
+                }),
+                "ext_sql_exec" => Function::new_native_with_env(store, riwaq_env.clone(), ext_sql_exec),
+                "ext_sql_query" => Function::new_native_with_env(store, riwaq_env.clone(), ext_sql_query),
+                "ext_custom_sql_exec" => Function::new_native_with_env(store, riwaq_env.clone(), ext_custom_sql_exec),
+                "ext_custom_sql_query" => Function::new_native_with_env(store, riwaq_env.clone(), ext_custom_sql_query),
+                "ext_sql_begin" => Function::new_native_with_env(store, riwaq_env.clone(), ext_sql_begin),
+                "ext_sql_commit" => Function::new_native_with_env(store, riwaq_env.clone(), ext_sql_commit),
+                "ext_sql_rollback" => Function::new_native_with_env(store, riwaq_env.clone(), ext_sql_rollback),
+                "ext_sql_cursor_open" => Function::new_native_with_env(store, riwaq_env.clone(), ext_sql_cursor_open),
+                "ext_custom_sql_cursor_open" => Function::new_native_with_env(store, riwaq_env.clone(), ext_custom_sql_cursor_open),
+                "ext_sql_cursor_next" => Function::new_native_with_env(store, riwaq_env.clone(), ext_sql_cursor_next),
+                "ext_sql_cursor_close" => Function::new_native_with_env(store, riwaq_env.clone(), ext_sql_cursor_close),
+                "ext_emit" => Function::new_native_with_env(store, riwaq_env, ext_emit)
+            }
+        });
+
+        let instance = Instance::new(&self.module, &objects).map_err(|e| -> Box<dyn Error> {
+            if take_limit_hit() {
+                Box::new(HandlerError::MemoryLimit(e.to_string()))
+            } else {
+                e.into()
+            }
+        })?;
+        Ok((instance, sql))
+    }
+
+    /// Checks out an idle instance, instantiating a new one while under `max`
+    pub async fn get(self: &Arc<Self>) -> Result<PooledInstance, Box<dyn Error>> {
+        let permit = self.permits.clone().acquire_owned().await?;
+        let idle = self.idle.lock().unwrap().pop();
+        let (instance, sql) = match idle {
+            Some(idle) => idle,
+            None => self.instantiate()?,
+        };
+        Ok(PooledInstance {
+            instance: Some(instance),
+            sql,
+            pool: self.clone(),
+            _permit: permit,
+        })
+    }
+
+    /// Calls the exported function `f` on a pooled instance within the pool's fuel and time budget
+    pub async fn call(self: &Arc<Self>, f: String, args: Value) -> Result<Value, HandlerError> {
+        let instance = self
+            .get()
+            .await
+            .map_err(|e| match e.downcast::<HandlerError>() {
+                Ok(e) => *e,
+                Err(e) => HandlerError::Failed(e.to_string()),
+            })?;
+        let fuel = self.config.fuel;
+        let handle = (*instance).clone();
+        let session = instance.sql.clone();
+        let call = session.begin_call();
+
+        let task = tokio::task::spawn_blocking(move || {
+            if instance.sql.is_cancelled() {
+                return Err(HandlerError::Timeout);
+            }
+            set_remaining_points(&instance, fuel);
+            let memory = instance
+                .exports
+                .get_memory("memory")
+                .map_err(|e| HandlerError::Failed(e.to_string()))?;
+            take_limit_hit();
+            let res = call_wasm(instance.exports.clone(), memory.view(), f, args);
+            tokio::runtime::Handle::current().block_on(instance.sql.reset());
+            match res {
+                Ok(v) => Ok(v),
+                Err(e) => {
+                    let exhausted =
+                        matches!(get_remaining_points(&instance), MeteringPoints::Exhausted);
+                    let cancelled = instance.sql.is_cancelled();
+                    instance.discard();
+                    if exhausted || cancelled {
+                        Err(HandlerError::Timeout)
+                    } else if take_limit_hit() {
+                        Err(HandlerError::MemoryLimit(e.to_string()))
+                    } else {
+                        Err(HandlerError::Failed(e.to_string()))
+                    }
+                }
+            }
+        });
+
+        match tokio::time::timeout(self.config.timeout, task).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => Err(HandlerError::Failed(e.to_string())),
+            Err(_) => {
+                // out of fuel the handler traps at its next metered block, and its host call in
+                // flight gives up, the task then discards the instance and frees its slot
+                set_remaining_points(&handle, 0);
+                session.cancel(call);
+                Err(HandlerError::Timeout)
+            }
+        }
+    }
+}
+
+/// An instance checked out of an [`InstancePool`], returned to it on drop
+pub struct PooledInstance {
+    instance: Option<Instance>,
+    sql: Arc<SqlSession>,
+    pool: WasmPool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledInstance {
+    /// Drops the instance instead of returning it, used after a trap may have left its memory inconsistent
+    pub fn discard(mut self) {
+        self.instance = None;
+    }
+}
+
+impl Deref for PooledInstance {
+    type Target = Instance;
+
+    fn deref(&self) -> &Instance {
+        self.instance.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledInstance {
+    fn drop(&mut self) {
+        if let Some(instance) = self.instance.take() {
+            self.pool
+                .idle
+                .lock()
+                .unwrap()
+                .push((instance, self.sql.clone()));
+        }
+    }
+}
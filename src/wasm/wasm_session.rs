@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use async_graphql::futures_util::stream::{self, Fuse, StreamExt};
+use serde::Deserialize;
+use sqlparser::{
+    ast::{SetExpr, Statement},
+    dialect::GenericDialect,
+    parser::Parser,
+};
+use tokio::sync::{Mutex, Notify};
+
+use crate::sql::driver::{
+    any::{AnyConn, AnyPool},
+    error::SqlError,
+    migration::Dialect,
+    model::{Conn, Pool, RowStream},
+};
+
+/// Query whose rows the database streams as batches are read, from a declared cursor on
+/// postgres
+pub struct Cursor {
+    /// declared before the connection it reads through so it's dropped first
+    pub rows: Fuse<RowStream>,
+    /// column names rows are keyed by, rows of custom sql stay arrays
+    pub cols: Option<Vec<String>>,
+    /// connection held by the cursor, none when it reads through the open transaction
+    _conn: Option<AnyConn>,
+}
+
+/// Input of `ext_sql_cursor_next` and `ext_sql_cursor_close`, `size` defaults to and is capped
+/// at `DB_MAX_ROWS`
+#[derive(Deserialize, Debug)]
+pub struct CursorRequest {
+    pub id: u32,
+    #[serde(default)]
+    pub size: Option<usize>,
+}
+
+/// Whether `sql` is a single query returning rows, a `SELECT`, `WITH … SELECT`, `VALUES`…
+pub fn is_query(sql: &str) -> bool {
+    match Parser::parse_sql(&GenericDialect {}, sql) {
+        Ok(stmts) => matches!(
+            stmts.as_slice(),
+            [Statement::Query(q)] if !matches!(*q.body, SetExpr::Insert(_) | SetExpr::Update(_))
+        ),
+        // syntax only the backend knows, a leading SELECT is enough then
+        Err(_) => sql
+            .trim_start_matches(|c: char| c == '(' || c.is_whitespace())
+            .split(|c: char| !c.is_ascii_alphabetic())
+            .next()
+            .unwrap_or_default()
+            .eq_ignore_ascii_case("SELECT"),
+    }
+}
+
+/// Sql state a handler keeps across host calls, reset by the instance pool once it returns
+#[derive(Default)]
+pub struct SqlSession {
+    /// transaction pinned to one connection by `ext_sql_begin`
+    pub tx: Mutex<Option<AnyConn>>,
+    pub cursors: Mutex<HashMap<u32, Cursor>>,
+    next_cursor: AtomicU32,
+    /// id of the handler call running on the instance
+    call: AtomicU64,
+    /// id of the last call cancelled by its timeout
+    cancelled: AtomicU64,
+    cancel: Notify,
+}
+
+impl SqlSession {
+    /// Starts a handler call, returning the id to cancel it with
+    pub fn begin_call(&self) -> u64 {
+        self.call.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Cancels call `id` if it's still running, its host calls then return an error
+    pub fn cancel(&self, id: u64) {
+        self.cancelled.store(id, Ordering::SeqCst);
+        self.cancel.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        let call = self.call.load(Ordering::SeqCst);
+        call != 0 && self.cancelled.load(Ordering::SeqCst) == call
+    }
+
+    /// Resolves once the running call is cancelled
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.cancel.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Opens a cursor over the query `sql`, read through the open transaction or else through
+    /// a connection of its own, in a transaction on postgres. Stepping sqlite rows holds their
+    /// connection, so a sqlite cursor opened in a transaction reads its rows upfront.
+    pub async fn open_cursor(
+        &self,
+        pool: AnyPool,
+        sql: String,
+        cols: Option<Vec<String>>,
+    ) -> Result<u32, SqlError> {
+        let tx = self.tx.lock().await;
+        let rows = match tx.as_ref() {
+            Some(conn) if conn.dialect() == Dialect::Sqlite => {
+                let rows = conn.custom_query(sql.clone()).await?;
+                Some(stream::iter(rows.into_iter().map(Ok)).boxed())
+            }
+            Some(conn) => Some(conn.stream(sql.clone()).await?),
+            None => None,
+        };
+        drop(tx);
+        let cursor = match rows {
+            Some(rows) => Cursor {
+                rows: rows.fuse(),
+                cols,
+                _conn: None,
+            },
+            None => {
+                let conn = pool.tx_conn().await?;
+                if conn.dialect() == Dialect::Postgres {
+                    conn.exec("BEGIN").await?;
+                }
+                Cursor {
+                    rows: conn.stream(sql).await?.fuse(),
+                    cols,
+                    _conn: Some(conn),
+                }
+            }
+        };
+        let id = self.next_cursor.fetch_add(1, Ordering::Relaxed);
+        self.cursors.lock().await.insert(id, cursor);
+        Ok(id)
+    }
+
+    /// Ends the open transaction with `stmt`, `COMMIT` or `ROLLBACK`. The connection is
+    /// discarded when it fails, the transaction may still be open on it.
+    pub async fn end_tx(&self, stmt: &str) -> Result<(), SqlError> {
+        let conn = self
+            .tx
+            .lock()
+            .await
+            .take()
+            .ok_or("no transaction is open")?;
+        let res = conn.exec(stmt).await.map(|_| ());
+        if res.is_err() {
+            conn.discard();
+        }
+        res
+    }
+
+    /// Closes the cursors and rolls back a transaction left open by a trap or a missing commit
+    pub async fn reset(&self) {
+        self.cursors.lock().await.clear();
+        if self.tx.lock().await.is_none() {
+            return;
+        }
+        if let Err(e) = self.end_tx("ROLLBACK").await {
+            tracing::warn!("rollback of an unfinished transaction failed: {}", e);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::{cell::Cell, ptr::NonNull, sync::Arc};
+
+use loupe::{MemoryUsage, MemoryUsageTracker};
+use wasmer::{
+    vm::{self, MemoryError, MemoryStyle, TableStyle, VMMemoryDefinition, VMTableDefinition},
+    MemoryType, Pages, TableType, Tunables,
+};
+
+thread_local! {
+    /// Set when a memory created or grown on this thread ran into its limit
+    static LIMIT_HIT: Cell<bool> = Cell::new(false);
+}
+
+/// Whether a memory created or grown on this thread ran into its limit since the last call,
+/// wasm runs on the thread calling into it so this tells why an instance trapped
+pub fn take_limit_hit() -> bool {
+    LIMIT_HIT.with(|hit| hit.replace(false))
+}
+
+/// Wraps base tunables to cap every linear memory of an org at `limit` pages,
+/// instantiation fails when a module asks for more and `memory.grow` past it returns -1.
+#[derive(MemoryUsage)]
+pub struct LimitingTunables<T: Tunables> {
+    limit: Pages,
+    base: T,
+}
+
+impl<T: Tunables> LimitingTunables<T> {
+    pub fn new(base: T, limit: Pages) -> Self {
+        Self { limit, base }
+    }
+
+    fn adjust_memory(&self, requested: &MemoryType) -> MemoryType {
+        let mut adjusted = *requested;
+        adjusted.maximum = Some(
+            requested
+                .maximum
+                .map_or(self.limit, |max| max.min(self.limit)),
+        );
+        adjusted
+    }
+
+    fn validate_memory(&self, ty: &MemoryType) -> Result<(), MemoryError> {
+        if ty.minimum > self.limit {
+            LIMIT_HIT.with(|hit| hit.set(true));
+            return Err(MemoryError::MinimumMemoryTooLarge {
+                min_requested: ty.minimum,
+                max_allowed: self.limit,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<T: Tunables> Tunables for LimitingTunables<T> {
+    fn memory_style(&self, memory: &MemoryType) -> MemoryStyle {
+        self.base.memory_style(&self.adjust_memory(memory))
+    }
+
+    fn table_style(&self, table: &TableType) -> TableStyle {
+        self.base.table_style(table)
+    }
+
+    fn create_host_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+    ) -> Result<Arc<dyn vm::Memory>, MemoryError> {
+        let adjusted = self.adjust_memory(ty);
+        self.validate_memory(&adjusted)?;
+        let memory = self.base.create_host_memory(&adjusted, style)?;
+        Ok(Arc::new(LimitedMemory(memory)))
+    }
+
+    unsafe fn create_vm_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+        vm_definition_location: NonNull<VMMemoryDefinition>,
+    ) -> Result<Arc<dyn vm::Memory>, MemoryError> {
+        let adjusted = self.adjust_memory(ty);
+        self.validate_memory(&adjusted)?;
+        let memory = self
+            .base
+            .create_vm_memory(&adjusted, style, vm_definition_location)?;
+        Ok(Arc::new(LimitedMemory(memory)))
+    }
+
+    fn create_host_table(
+        &self,
+        ty: &TableType,
+        style: &TableStyle,
+    ) -> Result<Arc<dyn vm::Table>, String> {
+        self.base.create_host_table(ty, style)
+    }
+
+    unsafe fn create_vm_table(
+        &self,
+        ty: &TableType,
+        style: &TableStyle,
+        vm_definition_location: NonNull<VMTableDefinition>,
+    ) -> Result<Arc<dyn vm::Table>, String> {
+        self.base.create_vm_table(ty, style, vm_definition_location)
+    }
+}
+
+/// Memory recording its failed grows for [`take_limit_hit`]
+#[derive(Debug)]
+struct LimitedMemory(Arc<dyn vm::Memory>);
+
+impl MemoryUsage for LimitedMemory {
+    fn size_of_val(&self, tracker: &mut dyn MemoryUsageTracker) -> usize {
+        std::mem::size_of_val(self) + self.0.size_of_val(tracker)
+    }
+}
+
+impl vm::Memory for LimitedMemory {
+    fn ty(&self) -> MemoryType {
+        self.0.ty()
+    }
+
+    fn style(&self) -> &MemoryStyle {
+        self.0.style()
+    }
+
+    fn size(&self) -> Pages {
+        self.0.size()
+    }
+
+    fn grow(&self, delta: Pages) -> Result<Pages, MemoryError> {
+        let res = self.0.grow(delta);
+        if res.is_err() {
+            LIMIT_HIT.with(|hit| hit.set(true));
+        }
+        res
+    }
+
+    fn vmmemory(&self) -> NonNull<VMMemoryDefinition> {
+        self.0.vmmemory()
+    }
+}